@@ -3,8 +3,8 @@ use criterion::{
     Throughput::Elements,
 };
 use itertools::Itertools;
+use smaps_profiler::MMPermissions;
 use std::{collections::HashSet, time::Duration};
-use untitled_smaps_poller::MMPermissions;
 
 fn delete(s: &mut String, c: char) -> bool {
     if let Some(i) = s.find(c) {
@@ -62,10 +62,10 @@ fn bench_fns(c: &mut Criterion) {
         for input in "bfrwxsp".chars().permutations(n as usize) {
             let input: String = input.into_iter().collect();
             group.bench_with_input(BenchmarkId::new("List", &input), &input, |b, i| {
-                b.iter_batched(|| i.clone(), |i| get_mask_list(i), SmallInput)
+                b.iter_batched(|| i.clone(), get_mask_list, SmallInput)
             });
             group.bench_with_input(BenchmarkId::new("HashSet", &input), &input, |b, i| {
-                b.iter_batched(|| i.clone(), |i| get_mask_set(i), SmallInput)
+                b.iter_batched(|| i.clone(), get_mask_set, SmallInput)
             });
         }
     }
@@ -14,84 +14,145 @@
  */
 
 use crate::{MemCategory::*, Tag::*};
-use clap::Parser;
+use clap::{Parser, Subcommand, ValueEnum};
 use env_logger::Builder;
 use log::{info, LevelFilter};
-use regex::Regex;
+use serde::Serialize;
+use smaps_profiler::cli::{self, ErrorArgs, SelectionArgs};
 use smaps_profiler::{
-    get_processes, get_smaps, sum_memory, FMask, MMPermissions, MemCategory, MemoryExt, ProcListing,
+    build_proc_trees, get_processes_with_root, get_smaps_validated, get_smaps_with,
+    group_by_cgroup, group_by_file, perms_string, sum_memory, vm_flags_string, CgroupListing,
+    CgroupMemory, Diff, FMask, MMPermissions, MaskedFileMapping, MemCategory, MemStats, MemoryExt,
+    Metric, ProcListing, ProcTree, Reconciliation, Rules, SmapsOptions, Snapshot, Vma, CGROUP_ROOT,
 };
+use std::collections::HashMap;
 use std::{
     cmp::{Ordering, Reverse},
     fs,
-    io::{self, BufWriter, Write},
-    path::PathBuf,
+    io::{self, BufReader, BufWriter, Write},
+    path::{Path, PathBuf},
     process,
+    str::FromStr,
 };
 
 #[derive(Parser)]
 #[command(version, about = "Takes a snapshot of a program's memory usage categories using /proc/<pid>/smaps.", long_about = None)]
+#[command(args_conflicts_with_subcommands = true)]
 struct Args {
-    ///Regex to match process cmdline against
-    regex: Option<String>,
+    #[command(subcommand)]
+    command: Option<Command>,
 
-    ///If --regex is given, include children of matched processes, even if they don't match the
-    ///regex.
-    #[arg(short = 'c', long, requires = "regex")]
-    match_children: bool,
+    #[command(flatten)]
+    selector: SelectionArgs,
 
-    ///Match the process for this program.
-    #[arg(short = 's', long, default_value_t = false)]
-    match_self: bool,
-
-    ///Fail if permission is denied to read a process's info. Default behavior is to skip the
-    ///process and continue running.
-    #[arg(short, long)]
-    fail_on_noperm: bool,
+    #[command(flatten)]
+    errors: ErrorArgs,
 
     ///A string of any combination of the characters "bfrwxsp" that specifies the mask to use
     ///when aggregating file-backed mappings. An empty string here (created by passing "-m=")
     ///will cause all of the mappings be aggregated into one entry. If the option is not present,
     ///the default behavior will be the same as passing "frwxsp".
-    #[arg(short, long)]
-    mask: Option<String>,
+    #[arg(short, long, global = true, value_parser = FMask::from_str)]
+    mask: Option<FMask>,
+
+    ///Which smaps counter to report, e.g. Pss, Rss, Private_Dirty, Shared_Clean, or Swap. Uss is
+    ///Private_Clean + Private_Dirty, the memory that would be freed if the process exited.
+    #[arg(short = 'M', long, global = true, default_value_t = Metric::Pss)]
+    metric: Metric,
+
+    ///Categorize mappings with the rules in the TOML file <FILE> before falling back to the
+    ///built-in categories. See the README for the format.
+    #[arg(long, value_name = "FILE", value_parser = cli::parse_rules)]
+    rules: Option<Rules>,
+
+    ///Instead of reporting memory usage, check each process's categorized total against its
+    ///smaps_rollup and report the discrepancy along with the mappings that were left out or counted
+    ///as 0
+    #[arg(long)]
+    validate: bool,
+
+    ///Instead of breaking down memory by category, print the process hierarchy with the memory of
+    ///each process and of its whole subtree
+    #[arg(short = 't', long, conflicts_with = "validate")]
+    tree: bool,
+
+    ///Instead of breaking down memory by category, list every mapping of each process with its
+    ///address range, size, flags, and path. With --save, the mappings are saved too.
+    #[arg(long, conflicts_with_all = ["tree", "validate"])]
+    vmas: bool,
+
+    ///Instead of breaking down memory by category, show how much memory killing each process would
+    ///free (its Uss) next to its proportional share (its Pss)
+    #[arg(long, conflicts_with_all = ["tree", "validate", "vmas"])]
+    savings: bool,
+
+    ///Instead of breaking down each process by file, break down each file-backed mapping by
+    ///process: how much the mapping costs across all processes, and which processes map it.
+    ///--mask works the same way, e.g. "-m f" adds up all of the mappings of each file.
+    #[arg(long, conflicts_with_all = ["tree", "validate", "vmas", "savings"])]
+    by_file: bool,
+
+    ///Save the snapshot to <FILE> as JSON, with every category and file-backed mapping, so it can
+    ///be loaded again later with --load
+    #[arg(long, value_name = "FILE", conflicts_with = "validate")]
+    save: Option<PathBuf>,
+
+    ///Load a snapshot that was saved with --save instead of reading processes
+    #[arg(long, value_name = "FILE", conflicts_with_all = ["regex", "match_children", "pids", "comm", "exe", "user", "ppid", "cgroup", "select", "any", "exclude", "match_self", "proc_root", "validate", "rules"])]
+    load: Option<PathBuf>,
 
     ///File to output info to (stdout if unspecified)
-    #[arg(short, long)]
+    #[arg(short, long, global = true)]
     output: Option<PathBuf>,
 
+    ///Add up the processes in each cgroup and report every cgroup instead of every process, along
+    ///with the cgroup's memory.current and memory.stat if they can be read
+    #[arg(long, conflicts_with_all = ["tree", "validate", "vmas", "savings", "by_file"])]
+    by_cgroup: bool,
+
+    ///How to write the breakdown by category. json, csv, and tsv are meant for scripts: they list
+    ///every category of the summary and of each process, including the small ones, with the fields
+    ///of file-backed mappings in columns of their own.
+    #[arg(long, value_enum, default_value_t = Format::Table, conflicts_with_all = ["tree", "validate", "vmas", "savings", "by_file", "by_cgroup"])]
+    format: Format,
+
+    ///Where the cgroup v2 hierarchy is mounted, for reading memory.current and memory.stat
+    #[arg(long, value_name = "DIR", default_value = CGROUP_ROOT)]
+    cgroup_root: PathBuf,
+
     ///Print warnings to stderr
-    #[arg(short = 'w', long)]
+    #[arg(short = 'w', long, global = true)]
     show_warnings: bool,
 
     ///Print info messages
-    #[arg(short, long)]
+    #[arg(short, long, global = true)]
     verbose: bool,
 }
 
-fn delete(s: &mut String, c: char) -> bool {
-    if let Some(i) = s.find(c) {
-        s.remove(i);
-        true
-    } else {
-        false
-    }
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+enum Format {
+    Table,
+    Json,
+    Csv,
+    Tsv,
 }
 
-fn get_mask(mut s: String) -> Result<FMask, ()> {
-    let is_self = delete(&mut s, 'b');
-    let path = delete(&mut s, 'f');
-    let mut perms = MMPermissions::NONE;
-    perms.set(MMPermissions::READ, delete(&mut s, 'r'));
-    perms.set(MMPermissions::WRITE, delete(&mut s, 'w'));
-    perms.set(MMPermissions::EXECUTE, delete(&mut s, 'x'));
-    perms.set(MMPermissions::SHARED, delete(&mut s, 's'));
-    perms.set(MMPermissions::PRIVATE, delete(&mut s, 'p'));
-    if s.is_empty() {
-        Ok(FMask::new(is_self, path, perms))
-    } else {
-        Err(())
-    }
+#[derive(Subcommand)]
+enum Command {
+    ///Compare two snapshots that were saved with --save and show what changed in each category,
+    ///sorted by the size of the change. --mask and --metric work the same as for a snapshot.
+    Diff {
+        ///The earlier snapshot
+        before: PathBuf,
+
+        ///The later snapshot
+        after: PathBuf,
+    },
+}
+
+fn exit_on_error<T>(e: smaps_profiler::Error) -> T {
+    eprintln!("{e}");
+    process::exit(1)
 }
 
 fn main() -> io::Result<()> {
@@ -107,38 +168,11 @@ fn main() -> io::Result<()> {
     } else {
         env_logger::init();
     }
-    let mask = match args.mask {
-        Some(s) => match get_mask(s.clone()) {
-            Ok(m) => m,
-            Err(_) => {
-                eprintln!("Invalid mask \"{s}\"");
-                process::exit(1)
-            }
-        },
-        None => FMask::new(false, true, MMPermissions::all()),
-    };
-    let regex = &args.regex.map(|r| match Regex::new(r.as_str()) {
-        Ok(r) => r,
-        Err(e) => {
-            eprintln!("{e}");
-            process::exit(1)
-        }
-    });
-    let procs = get_processes(
-        regex,
-        args.match_children,
-        args.match_self,
-        args.fail_on_noperm,
-    )
-    .unwrap();
-    if procs.is_empty() {
-        match regex {
-            Some(r) => println!("No processes match \"{r}\"."),
-            None => println!("No processes found."),
-        };
-        return Ok(());
-    }
-    let procs = get_smaps(procs, args.fail_on_noperm).unwrap();
+    let selection = args.selector.selection();
+    let mask = args
+        .mask
+        .unwrap_or_else(|| FMask::new(false, true, MMPermissions::all()));
+    let policy = args.errors.policy();
     let width = match terminal_size::terminal_size() {
         Some((w, _)) => w.0,
         None => {
@@ -147,46 +181,111 @@ fn main() -> io::Result<()> {
             DEFAULT
         }
     };
-    match args.output {
-        Some(path) => {
-            let mut writer = BufWriter::new(fs::File::open(path)?);
-            write_out_all(&mut writer, procs, &mask, width as usize)
-        }
+    if let Some(Command::Diff { before, after }) = args.command {
+        let before = load_snapshot(&before)?;
+        let after = load_snapshot(&after)?;
+        let mut writer = open_output(args.output)?;
+        write_diff(
+            &mut writer,
+            &before,
+            &after,
+            &mask,
+            args.metric,
+            width as usize,
+        )?;
+        return writer.flush();
+    }
+    let snapshot = match &args.load {
+        Some(path) => load_snapshot(path)?,
         None => {
-            let mut writer = BufWriter::new(io::stdout().lock());
-            write_out_all(&mut writer, procs, &mask, width as usize)
+            let procs = get_processes_with_root(&args.selector.proc_root, &selection, &policy)
+                .unwrap_or_else(exit_on_error);
+            if procs.is_empty() {
+                if selection.include.is_some() || selection.exclude.is_some() {
+                    println!("No processes match.");
+                } else {
+                    println!("No processes found.");
+                }
+                return Ok(());
+            }
+            if args.validate {
+                let procs = get_smaps_validated(procs, &policy).unwrap_or_else(exit_on_error);
+                let mut writer = open_output(args.output)?;
+                write_validation(&mut writer, procs, args.metric, width as usize)?;
+                return writer.flush();
+            }
+            let options = SmapsOptions {
+                rules: args.rules.clone().unwrap_or_default(),
+                keep_vmas: args.vmas,
+            };
+            Snapshot::new(get_smaps_with(procs, &policy, &options).unwrap_or_else(exit_on_error))
+        }
+    };
+    if let Some(path) = args.save {
+        let mut writer = BufWriter::new(fs::File::create(path)?);
+        serde_json::to_writer(&mut writer, &snapshot)?;
+        writer.flush()?;
+    }
+    let mut writer = open_output(args.output)?;
+    if args.tree {
+        write_tree(&mut writer, snapshot.procs, args.metric, width as usize)?;
+    } else if args.vmas {
+        write_vmas(&mut writer, snapshot.procs, args.metric, width as usize)?;
+    } else if args.savings {
+        write_savings(&mut writer, snapshot.procs, width as usize)?;
+    } else if args.by_file {
+        write_by_file(
+            &mut writer,
+            &snapshot.procs,
+            &mask,
+            args.metric,
+            width as usize,
+        )?;
+    } else if args.by_cgroup {
+        // the cgroups of a loaded snapshot may not even exist anymore
+        let cgroup_root = args.load.is_none().then_some(args.cgroup_root.as_path());
+        write_out_cgroups(
+            &mut writer,
+            snapshot.procs,
+            &mask,
+            args.metric,
+            width as usize,
+            cgroup_root,
+        )?;
+    } else if args.format == Format::Table {
+        write_out_all(
+            &mut writer,
+            snapshot.procs,
+            &mask,
+            args.metric,
+            width as usize,
+        )?;
+    } else {
+        let report = Report::new(snapshot.procs, &mask, args.metric);
+        match args.format {
+            Format::Json => {
+                serde_json::to_writer(&mut writer, &report)?;
+                writeln!(writer)?;
+            }
+            Format::Csv => write_delimited(&mut writer, &report, ',')?,
+            Format::Tsv => write_delimited(&mut writer, &report, '\t')?,
+            Format::Table => unreachable!(),
         }
     }
+    writer.flush()
 }
 
-fn display_perms(perms: MMPermissions, mask: MMPermissions) -> String {
-    let mut res = String::with_capacity(4);
-    if perms.contains(MMPermissions::READ) {
-        res.push('r');
-    } else if mask.contains(MMPermissions::READ) {
-        res.push('-');
-    }
-    if perms.contains(MMPermissions::WRITE) {
-        res.push('w');
-    } else if mask.contains(MMPermissions::WRITE) {
-        res.push('-');
-    }
-    if perms.contains(MMPermissions::EXECUTE) {
-        res.push('x');
-    } else if mask.contains(MMPermissions::EXECUTE) {
-        res.push('-');
-    }
-    if perms.contains(MMPermissions::SHARED) {
-        res.push('s');
-    } else if mask.contains(MMPermissions::SHARED) {
-        res.push('-');
-    }
-    if perms.contains(MMPermissions::PRIVATE) {
-        res.push('p');
-    } else if mask.contains(MMPermissions::PRIVATE) {
-        res.push('-');
-    }
-    res
+fn load_snapshot(path: &PathBuf) -> io::Result<Snapshot> {
+    Ok(serde_json::from_reader(BufReader::new(fs::File::open(
+        path,
+    )?))?)
+}
+
+fn open_output(path: Option<PathBuf>) -> io::Result<Box<dyn Write>> {
+    Ok(match path {
+        Some(path) => Box::new(BufWriter::new(fs::File::create(path)?)),
+        None => Box::new(BufWriter::new(io::stdout().lock())),
+    })
 }
 
 fn chop_str(s: &str, width: usize) -> Vec<String> {
@@ -195,7 +294,9 @@ fn chop_str(s: &str, width: usize) -> Vec<String> {
     for s in s.split('\n') {
         let mut cur = s;
         while !cur.is_empty() {
-            let (chunk, rest) = cur.split_at(cur.len().min(width));
+            // at a char boundary, so that paths like /home/josé don't get split in the middle
+            let end = cur.char_indices().nth(width).map_or(cur.len(), |(i, _)| i);
+            let (chunk, rest) = cur.split_at(end);
             v.push(chunk.to_string());
             cur = rest;
         }
@@ -221,8 +322,15 @@ impl Tag {
             Normal(Anonymous) => 6,
             Normal(Vsys) => 7,
             Small => 8,
-            Normal(Other(_)) => 9,
-            Normal(File(_)) => 10,
+            Normal(Unclassified) => 9,
+            Normal(Custom(_)) => 10,
+            Normal(NamedAnon(_)) => 11,
+            Normal(AnonShmem(_)) => 12,
+            Normal(Memfd(_)) => 13,
+            Normal(Hugetlb(_)) => 14,
+            Normal(Other(_)) => 15,
+            Normal(File(_)) => 16,
+            Normal(Deleted(_)) => 17,
         }
     }
 }
@@ -235,8 +343,13 @@ impl Ord for Tag {
                     return Ordering::Equal;
                 };
                 match (l, r) {
-                    (Other(l), Other(r)) => l.cmp(r),
-                    (File(l), File(r)) => {
+                    (Custom(l), Custom(r))
+                    | (NamedAnon(l), NamedAnon(r))
+                    | (AnonShmem(l), AnonShmem(r))
+                    | (Memfd(l), Memfd(r))
+                    | (Hugetlb(l), Hugetlb(r))
+                    | (Other(l), Other(r)) => l.cmp(r),
+                    (File(l), File(r)) | (Deleted(l), Deleted(r)) => {
                         (&l.path, Reverse(l.masked_perms)).cmp(&(&r.path, Reverse(r.masked_perms)))
                     }
                     _ => Ordering::Equal,
@@ -256,19 +369,23 @@ impl PartialOrd for Tag {
 #[derive(Clone, Debug, PartialEq, Eq)]
 struct Item {
     percent: u8,
-    pss: u64,
+    bytes: u64,
     tag: Tag,
 }
 
 impl Item {
-    fn new(percent: u8, pss: u64, tag: Tag) -> Item {
-        Item { percent, pss, tag }
+    fn new(percent: u8, bytes: u64, tag: Tag) -> Item {
+        Item {
+            percent,
+            bytes,
+            tag,
+        }
     }
 }
 
 impl Ord for Item {
     fn cmp(&self, other: &Self) -> Ordering {
-        (Reverse(self.pss), &self.tag).cmp(&(Reverse(other.pss), &other.tag))
+        (Reverse(self.bytes), &self.tag).cmp(&(Reverse(other.bytes), &other.tag))
     }
 }
 
@@ -278,28 +395,40 @@ impl PartialOrd for Item {
     }
 }
 
+fn file_to_label(f: &MaskedFileMapping, perms_mask: MMPermissions, unmasked: &str) -> String {
+    let path = match &f.path {
+        Some(path) => path.to_str().unwrap_or("<path not unicode>"),
+        None => unmasked,
+    };
+    let is_self = match &f.is_self {
+        Some(b) => {
+            if *b {
+                "(original executable)".to_string()
+            } else {
+                "(external)".to_string()
+            }
+        }
+        None => String::new(),
+    };
+    format!(
+        "{path} {} {is_self}",
+        perms_string::to_masked_string(f.masked_perms, perms_mask)
+    )
+}
+
 fn category_to_label(cat: MemCategory, perms_mask: MMPermissions) -> String {
     match cat {
-        File(f) => {
-            let path = match &f.path {
-                Some(path) => path.to_str().unwrap_or("<path not unicode>"),
-                None => "File-Backed Mappings",
-            };
-            let is_self = match &f.is_self {
-                Some(b) => {
-                    if *b {
-                        "(original executable)".to_string()
-                    } else {
-                        "(external)".to_string()
-                    }
-                }
-                None => String::new(),
-            };
-            format!(
-                "{path} {} {is_self}",
-                display_perms(f.masked_perms, perms_mask)
-            )
+        File(f) => file_to_label(&f, perms_mask, "File-Backed Mappings"),
+        Deleted(f) if f.path.is_some() => {
+            format!("{} (deleted)", file_to_label(&f, perms_mask, ""))
         }
+        Deleted(f) => file_to_label(&f, perms_mask, "Deleted File-Backed Mappings"),
+        NamedAnon(s) => format!("[anon:{s}]"),
+        AnonShmem(s) if s.is_empty() => "Shared Anonymous Mappings".to_string(),
+        AnonShmem(s) => format!("[anon_shmem:{s}]"),
+        Memfd(s) => format!("memfd:{s}"),
+        Hugetlb(s) => format!("{s} (hugetlbfs)"),
+        Custom(s) => s,
         Heap => "Heap".to_string(),
         Stack => "Stack".to_string(),
         TStack => "Thread Stack".to_string(),
@@ -310,6 +439,7 @@ fn category_to_label(cat: MemCategory, perms_mask: MMPermissions) -> String {
         Vsys => "Vsys".to_string(),
         Other(s) if s.is_empty() => "<other unnamed mapping>".to_string(),
         Other(s) => s,
+        Unclassified => "Unclassified".to_string(),
     }
 }
 
@@ -324,6 +454,7 @@ fn write_out<T, U>(
     out: &mut T,
     mem: MemoryExt,
     file_mask: &FMask,
+    metric: Metric,
     width: usize,
     mut header_hook: U,
 ) -> io::Result<()>
@@ -331,18 +462,19 @@ where
     T: Write,
     U: FnMut(&mut T, u64, usize) -> io::Result<()>,
 {
-    let total_mem = mem.total();
+    let total_mem = mem.total().get(metric);
     if total_mem == 0 {
         return Ok(());
     }
     let mut items: Vec<Item> = Vec::new();
     let mut small_total = 0;
-    for (cat, pss) in mem.iter_aggregate(file_mask) {
-        let percent = to_percent_rounded(pss, total_mem);
+    for (cat, stats) in mem.iter_aggregate(file_mask) {
+        let bytes = stats.get(metric);
+        let percent = to_percent_rounded(bytes, total_mem);
         if percent == 0 {
-            small_total += pss;
+            small_total += bytes;
         }
-        items.push(Item::new(percent as u8, pss, Normal(cat)));
+        items.push(Item::new(percent, bytes, Normal(cat)));
     }
     items.push(Item::new(
         to_percent_rounded(small_total, total_mem),
@@ -353,13 +485,18 @@ where
     const MIN_PATH: usize = 20;
     const PERCENT: usize = 4;
     const SEPS: usize = 2;
-    let u64_digits = (items.first().unwrap().pss.max(1).ilog10() + 1) as usize;
+    let u64_digits = (items.first().unwrap().bytes.max(1).ilog10() + 1) as usize;
     let width_nopath = PERCENT + u64_digits + 2 * SEPS;
     let width = width.max(width_nopath + MIN_PATH);
     let path_width = width - width_nopath;
     header_hook(out, total_mem, width)?;
     let mut small_header_printed = false;
-    for Item { percent, pss, tag } in items {
+    for Item {
+        percent,
+        bytes,
+        tag,
+    } in items
+    {
         let label;
         match tag {
             Normal(cat) => {
@@ -376,9 +513,12 @@ where
             }
         }
         let chunks = chop_str(&label, path_width);
-        let chunk = &chunks[0];
-        writeln!(out, "{percent:3}%  {pss:u64_digits$}  {chunk:path_width$}")?;
-        for chunk in &chunks[1..] {
+        let chunk = chunks.first().map_or("", String::as_str);
+        writeln!(
+            out,
+            "{percent:3}%  {bytes:u64_digits$}  {chunk:path_width$}"
+        )?;
+        for chunk in chunks.iter().skip(1) {
             writeln!(out, "{}{}", " ".repeat(width_nopath), chunk)?;
         }
     }
@@ -389,35 +529,694 @@ fn write_out_all<T: Write>(
     out: &mut T,
     mut procs: Vec<ProcListing>,
     file_mask: &FMask,
+    metric: Metric,
     width: usize,
 ) -> io::Result<()> {
-    procs.sort_unstable_by_key(|p| Reverse(p.memory_ext.total()));
+    procs.sort_unstable_by_key(|p| Reverse(p.memory_ext.total().get(metric)));
     let all = sum_memory(&procs);
     let header_hook = |out: &mut T, total, width| {
-        let header = chop_str(
-            &format!("Summary ({} processes)\nTotal: {total} bytes", procs.len()),
-            width,
+        let header = format!(
+            "Summary ({} processes)\nTotal {metric}: {total} bytes",
+            procs.len()
         );
-        writeln!(out, "{}", "-".repeat(width))?;
-        for line in header {
-            writeln!(out, "{}", line)?;
-        }
-        writeln!(out, "{}", "-".repeat(width))
+        write_header(out, &header, width)
     };
-    write_out(out, all, file_mask, width, header_hook)?;
+    write_out(out, all, file_mask, metric, width, header_hook)?;
     for proc in procs {
         let header_hook = |out: &mut T, total, width| {
-            let header = chop_str(
-                &format!("PID {}\n{}\nTotal: {total} bytes", proc.pid, proc.cmdline),
-                width,
+            let header = format!(
+                "PID {}\n{}\nTotal {metric}: {total} bytes",
+                proc.pid, proc.cmdline
+            );
+            write_header(out, &header, width)
+        };
+        write_out(out, proc.memory_ext, file_mask, metric, width, header_hook)?;
+    }
+    Ok(())
+}
+
+fn write_out_cgroups<T: Write>(
+    out: &mut T,
+    procs: Vec<ProcListing>,
+    file_mask: &FMask,
+    metric: Metric,
+    width: usize,
+    cgroup_root: Option<&Path>,
+) -> io::Result<()> {
+    let mut groups: Vec<CgroupListing> = group_by_cgroup(&procs);
+    groups.sort_unstable_by_key(|g| Reverse(g.memory_ext.total().get(metric)));
+    let all = sum_memory(&procs);
+    let header_hook = |out: &mut T, total, width| {
+        let header = format!(
+            "Summary ({} processes in {} cgroups)\nTotal {metric}: {total} bytes",
+            procs.len(),
+            groups.len()
+        );
+        write_header(out, &header, width)
+    };
+    write_out(out, all, file_mask, metric, width, header_hook)?;
+    for group in groups {
+        let name = group.cgroup.as_deref().unwrap_or("<unknown>");
+        let cgroup_memory = match (cgroup_root, &group.cgroup) {
+            (Some(root), Some(path)) => match CgroupMemory::read(root, path) {
+                Ok(mem) => Some(mem),
+                Err(e) => {
+                    info!("couldn't read the memory usage of cgroup {path}: {e}");
+                    None
+                }
+            },
+            _ => None,
+        };
+        let header_hook = |out: &mut T, total, width| {
+            let mut header = format!(
+                "Cgroup {name} ({} processes)\nTotal {metric}: {total} bytes",
+                group.pids.len()
             );
-            writeln!(out, "{}", "-".repeat(width))?;
-            for line in header {
-                writeln!(out, "{}", line)?;
+            if let Some(mem) = &cgroup_memory {
+                header.push_str(&format!(
+                    "\nmemory.current: {} bytes (anon {}, file {}, kernel {})",
+                    mem.current,
+                    mem.get("anon"),
+                    mem.get("file"),
+                    mem.get("kernel")
+                ));
+            }
+            write_header(out, &header, width)
+        };
+        write_out(out, group.memory_ext, file_mask, metric, width, header_hook)?;
+    }
+    Ok(())
+}
+
+#[derive(Serialize)]
+struct Report {
+    metric: String,
+    summary: Breakdown,
+    procs: Vec<Breakdown>,
+}
+
+/// The categories of one process, or of all of them when `pid` is `None`.
+#[derive(Serialize)]
+struct Breakdown {
+    pid: Option<i32>,
+    cmdline: Option<String>,
+    total: u64,
+    categories: Vec<CategoryRow>,
+}
+
+#[derive(Serialize)]
+struct CategoryRow {
+    category: &'static str,
+    name: Option<String>,
+    path: Option<PathBuf>,
+    is_self: Option<bool>,
+    perms: Option<String>,
+    bytes: u64,
+    percent: f64,
+}
+
+impl Report {
+    fn new(mut procs: Vec<ProcListing>, file_mask: &FMask, metric: Metric) -> Report {
+        procs.sort_unstable_by_key(|p| Reverse(p.memory_ext.total().get(metric)));
+        let summary = Breakdown::new(None, None, sum_memory(&procs), file_mask, metric);
+        let procs = procs
+            .into_iter()
+            .map(|p| {
+                Breakdown::new(
+                    Some(p.pid),
+                    Some(p.cmdline),
+                    p.memory_ext,
+                    file_mask,
+                    metric,
+                )
+            })
+            .collect();
+        Report {
+            metric: metric.to_string(),
+            summary,
+            procs,
+        }
+    }
+}
+
+impl Breakdown {
+    fn new(
+        pid: Option<i32>,
+        cmdline: Option<String>,
+        mem: MemoryExt,
+        file_mask: &FMask,
+        metric: Metric,
+    ) -> Breakdown {
+        let total = mem.total().get(metric);
+        let mut cats: Vec<(u64, Tag)> = mem
+            .iter_aggregate(file_mask)
+            .map(|(cat, stats)| (stats.get(metric), Normal(cat)))
+            .collect();
+        cats.sort_unstable_by(|(l, lt), (r, rt)| (Reverse(l), lt).cmp(&(Reverse(r), rt)));
+        let categories = cats
+            .into_iter()
+            .filter_map(|(bytes, tag)| match tag {
+                Normal(cat) => Some(CategoryRow::new(cat, file_mask.perms, bytes, total)),
+                Small => None,
+            })
+            .collect();
+        Breakdown {
+            pid,
+            cmdline,
+            total,
+            categories,
+        }
+    }
+}
+
+impl CategoryRow {
+    fn new(cat: MemCategory, perms_mask: MMPermissions, bytes: u64, total: u64) -> CategoryRow {
+        let (category, name, file) = match cat {
+            Stack => ("stack", None, None),
+            Heap => ("heap", None, None),
+            TStack => ("thread_stack", None, None),
+            Vdso => ("vdso", None, None),
+            Vvar => ("vvar", None, None),
+            Vsyscall => ("vsyscall", None, None),
+            Anonymous => ("anonymous", None, None),
+            Vsys => ("vsys", None, None),
+            File(f) => ("file", None, Some(f)),
+            Deleted(f) => ("deleted", None, Some(f)),
+            NamedAnon(s) => ("named_anon", Some(s), None),
+            AnonShmem(s) => ("anon_shmem", Some(s), None),
+            Memfd(s) => ("memfd", Some(s), None),
+            Hugetlb(s) => ("hugetlb", Some(s), None),
+            Custom(s) => ("custom", Some(s), None),
+            Other(s) => ("other", Some(s), None),
+            Unclassified => ("unclassified", None, None),
+        };
+        let (path, is_self, perms) = match file {
+            Some(f) => {
+                let perms = perms_string::to_masked_string(f.masked_perms, perms_mask);
+                (f.path, f.is_self, (!perms.is_empty()).then_some(perms))
+            }
+            None => (None, None, None),
+        };
+        let percent = if total == 0 {
+            0.0
+        } else {
+            bytes as f64 * 100.0 / total as f64
+        };
+        CategoryRow {
+            category,
+            name,
+            path,
+            is_self,
+            perms,
+            bytes,
+            percent,
+        }
+    }
+}
+
+fn write_delimited<T: Write>(out: &mut T, report: &Report, sep: char) -> io::Result<()> {
+    // csv quotes fields that need it, tsv has no quoting so the separators are replaced instead
+    let field = |s: &str| -> String {
+        if sep == ',' {
+            if s.contains([',', '"', '\n', '\r']) {
+                format!("\"{}\"", s.replace('"', "\"\""))
+            } else {
+                s.to_string()
             }
-            writeln!(out, "{}", "-".repeat(width))
+        } else {
+            s.replace(['\t', '\n', '\r'], " ")
+        }
+    };
+    let header = [
+        "PID", "CMDLINE", "CATEGORY", "NAME", "PATH", "IS_SELF", "PERMS", "BYTES", "PERCENT",
+    ];
+    writeln!(out, "{}", header.join(&sep.to_string()))?;
+    for breakdown in std::iter::once(&report.summary).chain(&report.procs) {
+        let pid = breakdown.pid.map(|p| p.to_string()).unwrap_or_default();
+        let cmdline = field(breakdown.cmdline.as_deref().unwrap_or(""));
+        for row in &breakdown.categories {
+            let path = row
+                .path
+                .as_ref()
+                .map(|p| field(&p.to_string_lossy()))
+                .unwrap_or_default();
+            let is_self = row.is_self.map(|b| b.to_string()).unwrap_or_default();
+            writeln!(
+                out,
+                "{pid}{sep}{cmdline}{sep}{}{sep}{}{sep}{path}{sep}{is_self}{sep}{}{sep}{}{sep}{:.2}",
+                row.category,
+                field(row.name.as_deref().unwrap_or("")),
+                row.perms.as_deref().unwrap_or(""),
+                row.bytes,
+                row.percent
+            )?;
+        }
+    }
+    Ok(())
+}
+
+fn write_header<T: Write>(out: &mut T, header: &str, width: usize) -> io::Result<()> {
+    writeln!(out, "{}", "-".repeat(width))?;
+    for line in chop_str(header, width) {
+        writeln!(out, "{}", line)?;
+    }
+    writeln!(out, "{}", "-".repeat(width))
+}
+
+fn write_tree<T: Write>(
+    out: &mut T,
+    procs: Vec<ProcListing>,
+    metric: Metric,
+    width: usize,
+) -> io::Result<()> {
+    let num_procs = procs.len();
+    let mut trees = build_proc_trees(procs);
+    let total: u64 = trees.iter().map(|t| t.subtree.total().get(metric)).sum();
+    write_header(
+        out,
+        &format!("Process tree ({num_procs} processes)\nTotal {metric}: {total} bytes"),
+        width,
+    )?;
+    let digits = (total.max(1).ilog10() + 1).max(7) as usize;
+    writeln!(out, "{:>digits$}  {:>digits$}  PROCESS", "SELF", "SUBTREE")?;
+
+    fn write_node<T: Write>(
+        out: &mut T,
+        tree: &mut ProcTree,
+        depth: usize,
+        metric: Metric,
+        digits: usize,
+        width: usize,
+    ) -> io::Result<()> {
+        let own = tree.proc.memory_ext.total().get(metric);
+        let subtree = tree.subtree.total().get(metric);
+        let label = format!(
+            "{}{} {}",
+            "  ".repeat(depth),
+            tree.proc.pid,
+            tree.proc.cmdline
+        );
+        let label_width = width.saturating_sub(2 * digits + 4).max(20);
+        let label: String = label
+            .chars()
+            .map(|c| if c.is_control() { ' ' } else { c })
+            .take(label_width)
+            .collect();
+        writeln!(out, "{own:>digits$}  {subtree:>digits$}  {label}")?;
+        sort_trees(&mut tree.children, metric);
+        for child in &mut tree.children {
+            write_node(out, child, depth + 1, metric, digits, width)?;
+        }
+        Ok(())
+    }
+
+    sort_trees(&mut trees, metric);
+    for tree in &mut trees {
+        write_node(out, tree, 0, metric, digits, width)?;
+    }
+    Ok(())
+}
+
+fn write_by_file<T: Write>(
+    out: &mut T,
+    procs: &[ProcListing],
+    file_mask: &FMask,
+    metric: Metric,
+    width: usize,
+) -> io::Result<()> {
+    let mut files = group_by_file(procs, file_mask);
+    files.sort_unstable_by(|l, r| {
+        (
+            Reverse(l.total.get(metric)),
+            &l.file.path,
+            Reverse(l.file.masked_perms),
+        )
+            .cmp(&(
+                Reverse(r.total.get(metric)),
+                &r.file.path,
+                Reverse(r.file.masked_perms),
+            ))
+    });
+    let total: u64 = files.iter().map(|f| f.total.get(metric)).sum();
+    write_header(
+        out,
+        &format!(
+            "File-backed mappings ({} files in {} processes)\nTotal {metric}: {total} bytes",
+            files.len(),
+            procs.len()
+        ),
+        width,
+    )?;
+    let digits = (total.max(1).ilog10() + 1).max(5) as usize;
+    writeln!(
+        out,
+        "{:>digits$}  PROCS  FILE",
+        metric.smaps_name().to_uppercase()
+    )?;
+    let label_width = width.saturating_sub(digits + 9).max(20);
+    let sanitize = |s: &str| -> String {
+        s.chars()
+            .map(|c| if c.is_control() { ' ' } else { c })
+            .take(label_width)
+            .collect()
+    };
+    for mut file in files {
+        let mut label = file_to_label(&file.file, file_mask.perms, "File-Backed Mappings");
+        if file.deleted {
+            label.push_str(" (deleted)");
+        }
+        writeln!(
+            out,
+            "{:>digits$}  {:>5}  {}",
+            file.total.get(metric),
+            file.procs.len(),
+            sanitize(label.trim_end())
+        )?;
+        file.procs
+            .sort_unstable_by_key(|p| (Reverse(p.stats.get(metric)), p.pid));
+        for user in &file.procs {
+            let label = format!("  {} {}", user.pid, user.cmdline);
+            writeln!(
+                out,
+                "{:>digits$}         {}",
+                user.stats.get(metric),
+                sanitize(&label)
+            )?;
+        }
+    }
+    Ok(())
+}
+
+fn write_savings<T: Write>(out: &mut T, procs: Vec<ProcListing>, width: usize) -> io::Result<()> {
+    let mut rows: Vec<(MemStats, ProcListing)> = procs
+        .into_iter()
+        .map(|p| (p.memory_ext.total(), p))
+        .collect();
+    rows.sort_unstable_by_key(|(total, p)| (Reverse(total.uss()), p.pid));
+    let all: MemStats = rows.iter().map(|(total, _)| *total).sum();
+    write_header(
+        out,
+        &format!(
+            "Savings if killed ({} processes)\nTotal Uss: {} bytes\nTotal Pss: {} bytes",
+            rows.len(),
+            all.uss(),
+            all.pss
+        ),
+        width,
+    )?;
+    let digits = (all.pss.max(all.uss()).max(1).ilog10() + 1).max(6) as usize;
+    writeln!(
+        out,
+        "{:>digits$}  {:>digits$}  {:>digits$}  USS%  PROCESS",
+        "USS", "PSS", "SHARED"
+    )?;
+    let label_width = width.saturating_sub(3 * digits + 12).max(20);
+    for (total, proc) in rows {
+        let uss = total.uss();
+        // Pss is rounded down for each mapping, so it can come out a little less than Uss
+        let shared = total.pss.saturating_sub(uss);
+        let percent = if total.pss == 0 {
+            0
+        } else {
+            to_percent_rounded(uss.min(total.pss), total.pss)
+        };
+        let label: String = format!("{} {}", proc.pid, proc.cmdline)
+            .chars()
+            .map(|c| if c.is_control() { ' ' } else { c })
+            .take(label_width)
+            .collect();
+        writeln!(
+            out,
+            "{uss:>digits$}  {:>digits$}  {shared:>digits$}  {percent:>3}%  {label}",
+            total.pss
+        )?;
+    }
+    Ok(())
+}
+
+fn write_vmas<T: Write>(
+    out: &mut T,
+    mut procs: Vec<ProcListing>,
+    metric: Metric,
+    width: usize,
+) -> io::Result<()> {
+    procs.sort_unstable_by_key(|p| Reverse(p.memory_ext.total().get(metric)));
+    for proc in procs {
+        let Some(vmas) = &proc.vmas else {
+            writeln!(
+                out,
+                "PID {} has no mappings. Was the snapshot saved without --vmas?",
+                proc.pid
+            )?;
+            continue;
         };
-        write_out(out, proc.memory_ext, file_mask, width, header_hook)?;
+        let header = format!(
+            "PID {}\n{}\n{} mappings, total {metric}: {} bytes",
+            proc.pid,
+            proc.cmdline,
+            vmas.len(),
+            proc.memory_ext.total().get(metric)
+        );
+        write_header(out, &header, width)?;
+        let m = metric.smaps_name().to_uppercase();
+        let max_size = vmas.iter().map(|v| v.size()).max().unwrap_or(0);
+        let digits = (max_size.max(1).ilog10() as usize + 1).max(m.len());
+        let flags_width = vmas
+            .iter()
+            .map(|v| vm_flags_string::to_string(v.vm_flags).len())
+            .max()
+            .unwrap_or(0)
+            .max(5);
+        writeln!(
+            out,
+            "{:<33}  {:>digits$}  {m:>digits$}  PERMS  {:<flags_width$}  PATH",
+            "ADDRESS", "SIZE", "FLAGS"
+        )?;
+        for vma in vmas {
+            writeln!(out, "{}", vma_to_line(vma, metric, digits, flags_width))?;
+        }
     }
     Ok(())
 }
+
+fn vma_to_line(vma: &Vma, metric: Metric, digits: usize, flags_width: usize) -> String {
+    let Vma {
+        start,
+        end,
+        perms,
+        pathname,
+        vm_flags,
+        category,
+        stats,
+        ..
+    } = vma;
+    let perms = perms_string::to_string(*perms);
+    let flags = vm_flags_string::to_string(*vm_flags);
+    let path = match category {
+        Custom(c) if pathname.is_empty() => format!("({c})"),
+        Custom(c) => format!("{pathname} ({c})"),
+        _ => pathname.clone(),
+    };
+    let path: String = path
+        .chars()
+        .map(|c| if c.is_control() { ' ' } else { c })
+        .collect();
+    format!(
+        "{start:016x}-{end:016x}  {:>digits$}  {:>digits$}  {perms:<5}  {flags:<flags_width$}  {path}",
+        vma.size(),
+        stats.get(metric)
+    )
+}
+
+fn sort_trees(trees: &mut [ProcTree], metric: Metric) {
+    trees.sort_unstable_by_key(|t| (Reverse(t.subtree.total().get(metric)), t.proc.pid));
+}
+
+fn write_validation<T: Write>(
+    out: &mut T,
+    mut procs: Vec<(ProcListing, Reconciliation)>,
+    metric: Metric,
+    width: usize,
+) -> io::Result<()> {
+    procs.sort_unstable_by_key(|(_, r)| Reverse(r.discrepancy(metric).abs()));
+    let total_discrepancy: i64 = procs.iter().map(|(_, r)| r.discrepancy(metric)).sum();
+    let unexplained = procs
+        .iter()
+        .filter(|(_, r)| !r.is_explained(metric) || !r.skipped.is_empty())
+        .count();
+    write_header(
+        out,
+        &format!(
+            "Validation against smaps_rollup ({} processes)\nTotal {metric} discrepancy: {total_discrepancy} bytes\n{unexplained} processes not explained by rounding alone",
+            procs.len()
+        ),
+        width,
+    )?;
+    for (proc, reconciliation) in procs {
+        write_header(out, &format!("PID {}\n{}", proc.pid, proc.cmdline), width)?;
+        let lines = [
+            format!(
+                "smaps_rollup {metric}: {} bytes",
+                reconciliation.rollup.get(metric)
+            ),
+            format!(
+                "Sum of categories: {} bytes",
+                reconciliation.total.get(metric)
+            ),
+            format!(
+                "Discrepancy: {} bytes (rounding explains up to {} bytes across {} mappings)",
+                reconciliation.discrepancy(metric),
+                reconciliation.rounding_bound(metric),
+                reconciliation.num_mappings
+            ),
+        ];
+        for line in lines {
+            for chunk in chop_str(&line, width) {
+                writeln!(out, "{}", chunk)?;
+            }
+        }
+        if !reconciliation.skipped.is_empty() {
+            writeln!(out, "Mappings left out or counted as 0:")?;
+            for skipped in &reconciliation.skipped {
+                for chunk in chop_str(&skipped.to_string(), width - 2) {
+                    writeln!(out, "  {}", chunk)?;
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+fn write_diff<T: Write>(
+    out: &mut T,
+    before: &Snapshot,
+    after: &Snapshot,
+    file_mask: &FMask,
+    metric: Metric,
+    width: usize,
+) -> io::Result<()> {
+    let mut procs = before.diff(after);
+    procs.sort_unstable_by_key(|p| (Reverse(p.change(metric).abs()), p.pid()));
+    let new = procs.iter().filter(|p| p.is_new()).count();
+    let gone = procs.iter().filter(|p| p.is_gone()).count();
+    let all = sum_memory(&before.procs).diff(&sum_memory(&after.procs), file_mask);
+    let header = format!(
+        "Summary ({} processes before, {} after, {new} new, {gone} gone)",
+        before.procs.len(),
+        after.procs.len()
+    );
+    write_diff_table(out, all, &header, file_mask, metric, width)?;
+    for proc in procs {
+        let status = if proc.is_new() {
+            "\nNew process"
+        } else if proc.is_gone() {
+            "\nGone process"
+        } else if proc.change(metric) == 0 {
+            continue;
+        } else {
+            ""
+        };
+        let header = format!("PID {}\n{}{status}", proc.pid(), proc.cmdline());
+        write_diff_table(
+            out,
+            proc.memory_diff(file_mask),
+            &header,
+            file_mask,
+            metric,
+            width,
+        )?;
+    }
+    Ok(())
+}
+
+fn write_diff_table<T: Write>(
+    out: &mut T,
+    diffs: HashMap<MemCategory, Diff<MemStats>>,
+    header: &str,
+    file_mask: &FMask,
+    metric: Metric,
+    width: usize,
+) -> io::Result<()> {
+    let get = |stats: Option<MemStats>| stats.map_or(0, |s| s.get(metric));
+    let total_before: u64 = diffs.values().map(|d| get(d.before)).sum();
+    let total_after: u64 = diffs.values().map(|d| get(d.after)).sum();
+    let total_change = total_after as i64 - total_before as i64;
+    write_header(
+        out,
+        &format!(
+            "{header}\nTotal {metric}: {total_before} -> {total_after} bytes ({total_change:+})"
+        ),
+        width,
+    )?;
+    let mut items: Vec<(i64, Diff<MemStats>, Tag)> = diffs
+        .into_iter()
+        .map(|(cat, diff)| (diff.change(metric), diff, Normal(cat)))
+        .collect();
+    items.sort_unstable_by(|(l, _, l_tag), (r, _, r_tag)| {
+        (Reverse(l.abs()), l_tag).cmp(&(Reverse(r.abs()), r_tag))
+    });
+    let unchanged = items
+        .iter()
+        .filter(|(change, diff, _)| *change == 0 && !diff.is_new() && !diff.is_gone())
+        .count();
+    let digits = |n: u64| (n.max(1).ilog10() + 1) as usize;
+    let max = items
+        .iter()
+        .map(|(change, diff, _)| {
+            change
+                .unsigned_abs()
+                .max(get(diff.before))
+                .max(get(diff.after))
+        })
+        .max()
+        .unwrap_or(0);
+    let u64_digits = digits(max);
+    const MIN_PATH: usize = 20;
+    const SEPS: usize = 2;
+    const ARROW: usize = 4;
+    let width_nopath = (u64_digits + 1) + SEPS + u64_digits + ARROW + u64_digits + SEPS;
+    let path_width = width.max(width_nopath + MIN_PATH) - width_nopath;
+    for (change, diff, tag) in items {
+        if change == 0 && !diff.is_new() && !diff.is_gone() {
+            continue;
+        }
+        let Normal(cat) = tag else { unreachable!() };
+        let mut label = category_to_label(cat, file_mask.perms);
+        if diff.is_new() {
+            label.push_str(" (new)");
+        } else if diff.is_gone() {
+            label.push_str(" (gone)");
+        }
+        let side = |stats: Option<MemStats>| match stats {
+            Some(stats) => stats.get(metric).to_string(),
+            None => "-".to_string(),
+        };
+        let (before, after) = (side(diff.before), side(diff.after));
+        let change_width = u64_digits + 1;
+        let chunks = chop_str(&label, path_width);
+        writeln!(
+            out,
+            "{change:+change_width$}  {before:>u64_digits$} -> {after:>u64_digits$}  {}",
+            chunks.first().map_or("", String::as_str)
+        )?;
+        for chunk in chunks.iter().skip(1) {
+            writeln!(out, "{}{}", " ".repeat(width_nopath), chunk)?;
+        }
+    }
+    if unchanged > 0 {
+        writeln!(out, "({unchanged} unchanged categories not shown)")?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn chop_str_splits_at_chars() {
+        assert_eq!(chop_str("/home/josé/a", 9), ["/home/jos", "é/a"]);
+        assert_eq!(chop_str("/home/josé/a", 10), ["/home/josé", "/a"]);
+        assert_eq!(chop_str("ab\ncd", 1), ["a", "b", "c", "d"]);
+    }
+}
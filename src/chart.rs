@@ -0,0 +1,448 @@
+/* Copyright 2025 Andrew Riachi
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, version 3 only.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Draws the stacked memory graph as SVG or PNG without any external programs.
+
+use resvg::{tiny_skia, usvg};
+use std::fmt::Write as _;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+const FONT_SIZE: f64 = 12.0;
+const FONT_FAMILY: &str = "DejaVu Sans, Liberation Sans, Arial, Helvetica, sans-serif";
+/// A guess at the average width of a character, since the fonts aren't known until the SVG is
+/// rendered
+const CHAR_WIDTH: f64 = FONT_SIZE * 0.6;
+const LEGEND_ROW: f64 = FONT_SIZE * 1.5;
+const SWATCH: f64 = FONT_SIZE;
+const MARGIN: f64 = 20.0;
+/// Room for the tick labels and the label of a y axis
+const Y_AXIS_WIDTH: f64 = 80.0;
+/// Room for the tick labels and the label of the x axis
+const X_AXIS_HEIGHT: f64 = 50.0;
+
+/// One band of the stacked area chart.
+pub struct Layer {
+    pub label: String,
+    /// Any SVG color, e.g. "#58b5e1"
+    pub color: &'static str,
+    /// Bytes, one for each of `Chart::times`
+    pub values: Vec<u64>,
+}
+
+pub struct Chart {
+    /// Seconds since the start of the run
+    pub times: Vec<f64>,
+    /// From the bottom of the stack to the top
+    pub layers: Vec<Layer>,
+    /// Without a unit, which is picked to fit the values
+    pub y_label: String,
+    /// Drawn as a line against a second y axis, one for each of `times`
+    pub faults: Option<Vec<u64>>,
+    pub faults_label: String,
+}
+
+impl Chart {
+    /// Saves the chart as PNG if `path` ends in ".png", or as SVG otherwise.
+    pub fn save(&self, path: &Path, width: u32, height: u32) -> io::Result<()> {
+        let is_png = path
+            .extension()
+            .is_some_and(|ext| ext.eq_ignore_ascii_case("png"));
+        if is_png {
+            self.save_png(path, width, height)
+        } else {
+            fs::write(path, self.to_svg(width, height))
+        }
+    }
+
+    pub fn save_png(&self, path: &Path, width: u32, height: u32) -> io::Result<()> {
+        let mut options = usvg::Options::default();
+        let fontdb = options.fontdb_mut();
+        fontdb.load_system_fonts();
+        // sans-serif means Arial unless told otherwise, so fall back to any font at all
+        let fallback = fontdb
+            .faces()
+            .find_map(|face| face.families.first().map(|(family, _)| family.clone()));
+        if let Some(family) = fallback {
+            fontdb.set_sans_serif_family(family);
+        }
+        let tree = usvg::Tree::from_str(&self.to_svg(width, height), &options)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        let size = tree.size().to_int_size();
+        let mut pixmap = tiny_skia::Pixmap::new(size.width(), size.height())
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "graph is empty"))?;
+        resvg::render(&tree, tiny_skia::Transform::default(), &mut pixmap.as_mut());
+        pixmap.save_png(path).map_err(io::Error::other)
+    }
+
+    /// Renders the chart with the legend to the right of the plot. The SVG is made taller than
+    /// `height` if that's what it takes to fit the legend.
+    pub fn to_svg(&self, width: u32, height: u32) -> String {
+        if let [time] = self.times[..] {
+            // a single sample is drawn a second wide so that there is something to see
+            let chart = Chart {
+                times: vec![time - 0.5, time + 0.5],
+                layers: self
+                    .layers
+                    .iter()
+                    .map(|l| Layer {
+                        label: l.label.clone(),
+                        color: l.color,
+                        values: l.values.repeat(2),
+                    })
+                    .collect(),
+                y_label: self.y_label.clone(),
+                faults: self.faults.as_ref().map(|f| f.repeat(2)),
+                faults_label: self.faults_label.clone(),
+            };
+            return chart.to_svg(width, height);
+        }
+        let label_chars = self
+            .layers
+            .iter()
+            .map(|l| l.label.chars().count())
+            .max()
+            .unwrap_or(0);
+        // long labels are cut short rather than squeezing the plot
+        let max_label_chars = ((width as f64 * 0.4 - SWATCH - MARGIN) / CHAR_WIDTH) as usize;
+        let label_chars = label_chars.min(max_label_chars);
+        let legend_width = SWATCH + 6.0 + label_chars as f64 * CHAR_WIDTH + MARGIN;
+        let legend_height = self.layers.len() as f64 * LEGEND_ROW;
+        let width = width as f64;
+        let height = (height as f64).max(legend_height + 2.0 * MARGIN);
+        let y2_width = if self.faults.is_some() {
+            Y_AXIS_WIDTH
+        } else {
+            MARGIN
+        };
+        let plot = Rect {
+            left: Y_AXIS_WIDTH,
+            top: MARGIN,
+            right: (width - legend_width - y2_width).max(Y_AXIS_WIDTH + 100.0),
+            bottom: height - X_AXIS_HEIGHT,
+        };
+
+        let mut svg = String::new();
+        writeln!(
+            svg,
+            r#"<svg xmlns="http://www.w3.org/2000/svg" width="{width}" height="{height}" viewBox="0 0 {width} {height}" font-family="{FONT_FAMILY}" font-size="{FONT_SIZE}">"#
+        )
+        .unwrap();
+        writeln!(svg, r#"<rect width="100%" height="100%" fill="white"/>"#).unwrap();
+
+        let (t0, t1) = match (self.times.first(), self.times.last()) {
+            (Some(&first), Some(&last)) if last > first => (first, last),
+            _ => (0.0, 1.0),
+        };
+        let x_of = |t: f64| plot.left + (t - t0) / (t1 - t0) * plot.width();
+
+        // the top of the stack at each sample
+        let mut tops = vec![0u64; self.times.len()];
+        for layer in &self.layers {
+            for (top, value) in tops.iter_mut().zip(&layer.values) {
+                *top += value;
+            }
+        }
+        let y_max = tops.iter().copied().max().unwrap_or(0).max(1) as f64;
+        let (unit, divisor) = byte_unit(y_max);
+        let y_step = nice_step(y_max / divisor, 5) * divisor;
+        let y_max = (y_max / y_step).ceil() * y_step;
+        let y_of = |v: f64| plot.bottom - v / y_max * plot.height();
+
+        // grid and y axis
+        let mut tick = 0.0;
+        while tick <= y_max * (1.0 + 1e-9) {
+            let y = y_of(tick);
+            writeln!(
+                svg,
+                r##"<line x1="{}" y1="{y:.1}" x2="{}" y2="{y:.1}" stroke="#dddddd"/>"##,
+                plot.left, plot.right
+            )
+            .unwrap();
+            writeln!(
+                svg,
+                r#"<text x="{}" y="{y:.1}" text-anchor="end" dominant-baseline="middle">{}</text>"#,
+                plot.left - 6.0,
+                format_number(tick / divisor, y_step / divisor)
+            )
+            .unwrap();
+            tick += y_step;
+        }
+        writeln!(
+            svg,
+            r#"<text transform="translate({}, {:.1}) rotate(-90)" text-anchor="middle">{}</text>"#,
+            MARGIN,
+            (plot.top + plot.bottom) / 2.0,
+            escape(&format!("{} ({unit})", self.y_label))
+        )
+        .unwrap();
+
+        // the stack, each layer as a polygon between its bottom and its top
+        let mut bottoms = vec![0u64; self.times.len()];
+        let xs: Vec<f64> = self.times.iter().map(|&t| x_of(t)).collect();
+        for layer in &self.layers {
+            let tops: Vec<u64> = bottoms
+                .iter()
+                .zip(&layer.values)
+                .map(|(b, v)| b + v)
+                .collect();
+            let mut points = String::new();
+            for (x, &v) in xs.iter().zip(&tops) {
+                write!(points, "{x:.1},{:.1} ", y_of(v as f64)).unwrap();
+            }
+            for (x, &v) in xs.iter().zip(&bottoms).rev() {
+                write!(points, "{x:.1},{:.1} ", y_of(v as f64)).unwrap();
+            }
+            writeln!(
+                svg,
+                r#"<polygon points="{}" fill="{}" fill-opacity="0.7" stroke="none"><title>{}</title></polygon>"#,
+                points.trim_end(),
+                layer.color,
+                escape(&layer.label)
+            )
+            .unwrap();
+            bottoms = tops;
+        }
+
+        // x axis
+        let (t_step, time_format) = time_ticks(t1 - t0);
+        let mut tick = (t0 / t_step).ceil() * t_step;
+        while tick <= t1 + t_step * 1e-9 {
+            let x = x_of(tick);
+            writeln!(
+                svg,
+                r#"<line x1="{x:.1}" y1="{}" x2="{x:.1}" y2="{}" stroke="black"/>"#,
+                plot.bottom,
+                plot.bottom + 4.0
+            )
+            .unwrap();
+            writeln!(
+                svg,
+                r#"<text x="{x:.1}" y="{}" text-anchor="middle">{}</text>"#,
+                plot.bottom + 4.0 + FONT_SIZE,
+                time_format.format(tick, t_step)
+            )
+            .unwrap();
+            tick += t_step;
+        }
+        writeln!(
+            svg,
+            r#"<text x="{:.1}" y="{}" text-anchor="middle">Time ({})</text>"#,
+            (plot.left + plot.right) / 2.0,
+            height - MARGIN / 2.0,
+            time_format.name()
+        )
+        .unwrap();
+        writeln!(
+            svg,
+            r#"<rect x="{}" y="{}" width="{}" height="{}" fill="none" stroke="black"/>"#,
+            plot.left,
+            plot.top,
+            plot.width(),
+            plot.height()
+        )
+        .unwrap();
+
+        // faults against the second y axis
+        if let Some(faults) = &self.faults {
+            let f_max = faults.iter().copied().max().unwrap_or(0).max(1) as f64;
+            let f_step = nice_step(f_max, 5).max(1.0);
+            let f_max = (f_max / f_step).ceil() * f_step;
+            let f_y = |v: f64| plot.bottom - v / f_max * plot.height();
+            let mut tick = 0.0;
+            while tick <= f_max * (1.0 + 1e-9) {
+                writeln!(
+                    svg,
+                    r#"<text x="{}" y="{:.1}" dominant-baseline="middle">{}</text>"#,
+                    plot.right + 6.0,
+                    f_y(tick),
+                    format_number(tick, f_step)
+                )
+                .unwrap();
+                tick += f_step;
+            }
+            let points: Vec<String> = xs
+                .iter()
+                .zip(faults)
+                .map(|(x, &v)| format!("{x:.1},{:.1}", f_y(v as f64)))
+                .collect();
+            writeln!(
+                svg,
+                r#"<polyline points="{}" fill="none" stroke="black" stroke-width="1.5"/>"#,
+                points.join(" ")
+            )
+            .unwrap();
+            writeln!(
+                svg,
+                r#"<text transform="translate({:.1}, {:.1}) rotate(90)" text-anchor="middle">{}</text>"#,
+                // rotated this way, the text is right of its position
+                plot.right + y2_width - FONT_SIZE - 6.0,
+                (plot.top + plot.bottom) / 2.0,
+                escape(&self.faults_label)
+            )
+            .unwrap();
+        }
+
+        // the legend, with the top of the stack first like the chart
+        let legend_left = plot.right + y2_width;
+        for (i, layer) in self.layers.iter().rev().enumerate() {
+            let y = MARGIN + i as f64 * LEGEND_ROW;
+            writeln!(
+                svg,
+                r#"<rect x="{legend_left:.1}" y="{y:.1}" width="{SWATCH}" height="{SWATCH}" fill="{}" fill-opacity="0.7"/>"#,
+                layer.color
+            )
+            .unwrap();
+            writeln!(
+                svg,
+                r#"<text x="{:.1}" y="{:.1}" dominant-baseline="middle">{}</text>"#,
+                legend_left + SWATCH + 6.0,
+                y + SWATCH / 2.0,
+                escape(&truncate(&layer.label, label_chars))
+            )
+            .unwrap();
+        }
+        svg.push_str("</svg>\n");
+        svg
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Rect {
+    left: f64,
+    top: f64,
+    right: f64,
+    bottom: f64,
+}
+
+impl Rect {
+    fn width(&self) -> f64 {
+        self.right - self.left
+    }
+
+    fn height(&self) -> f64 {
+        self.bottom - self.top
+    }
+}
+
+/// How to write the times on the x axis
+#[derive(Clone, Copy)]
+enum TimeFormat {
+    Seconds,
+    Minutes,
+    Hours,
+}
+
+impl TimeFormat {
+    fn name(&self) -> &'static str {
+        match self {
+            TimeFormat::Seconds => "s",
+            TimeFormat::Minutes => "m:ss",
+            TimeFormat::Hours => "h:mm:ss",
+        }
+    }
+
+    fn format(&self, secs: f64, step: f64) -> String {
+        match self {
+            TimeFormat::Seconds => format_number(secs, step),
+            TimeFormat::Minutes => {
+                let secs = secs.round() as u64;
+                format!("{}:{:02}", secs / 60, secs % 60)
+            }
+            TimeFormat::Hours => {
+                let secs = secs.round() as u64;
+                format!("{}:{:02}:{:02}", secs / 3600, secs / 60 % 60, secs % 60)
+            }
+        }
+    }
+}
+
+/// The distance between ticks on the x axis for a run that lasted `span` seconds, at whole
+/// seconds, minutes, or hours once the run is long enough.
+fn time_ticks(span: f64) -> (f64, TimeFormat) {
+    const STEPS: [f64; 14] = [
+        1.0, 2.0, 5.0, 10.0, 15.0, 30.0, 60.0, 120.0, 300.0, 600.0, 900.0, 1800.0, 3600.0, 7200.0,
+    ];
+    let format = if span < 120.0 {
+        TimeFormat::Seconds
+    } else if span < 7200.0 {
+        TimeFormat::Minutes
+    } else {
+        TimeFormat::Hours
+    };
+    let min_step = span / 10.0;
+    let step = if min_step < 1.0 {
+        nice_step(span, 10)
+    } else {
+        STEPS
+            .into_iter()
+            .find(|&s| s >= min_step)
+            .unwrap_or_else(|| (min_step / 3600.0).ceil() * 3600.0)
+    };
+    (step, format)
+}
+
+/// A step of 1, 2, or 5 times a power of 10 that divides `range` into at most about `ticks` parts.
+fn nice_step(range: f64, ticks: usize) -> f64 {
+    let raw = range / ticks as f64;
+    let magnitude = 10f64.powf(raw.log10().floor());
+    [1.0, 2.0, 5.0, 10.0]
+        .into_iter()
+        .map(|m| m * magnitude)
+        .find(|&s| s >= raw)
+        .unwrap_or(10.0 * magnitude)
+}
+
+/// The unit that bytes up to `max` are best shown in, along with its size in bytes.
+fn byte_unit(max: f64) -> (&'static str, f64) {
+    // kB are 1000 bytes, as in the TSV and the gnuplot graph
+    if max >= 1e9 {
+        ("GB", 1e9)
+    } else if max >= 1e6 {
+        ("MB", 1e6)
+    } else if max >= 1e3 {
+        ("KB", 1e3)
+    } else {
+        ("B", 1.0)
+    }
+}
+
+/// Formats `value` with as many decimals as `step` needs.
+fn format_number(value: f64, step: f64) -> String {
+    let decimals = if step >= 1.0 {
+        0
+    } else {
+        (-step.log10().floor()) as usize
+    };
+    format!("{value:.decimals$}")
+}
+
+fn truncate(s: &str, max_chars: usize) -> String {
+    if s.chars().count() <= max_chars {
+        s.to_string()
+    } else {
+        let mut s: String = s.chars().take(max_chars.saturating_sub(1)).collect();
+        s.push('…');
+        s
+    }
+}
+
+fn escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
@@ -0,0 +1,168 @@
+/* Copyright 2025 Andrew Riachi
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, version 3 only.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Command line arguments that smaps-profiler and smaps-snapshot have in common, to be flattened
+//! into their own `Args` with `#[command(flatten)]`.
+
+use crate::{uid_from_user, Action, ErrorKind, ErrorPolicy, Rules, Selection, Selector, PROC_ROOT};
+use clap::Args;
+use regex::Regex;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+/// Which processes to read, and where to read them from.
+#[derive(Args, Clone, Debug)]
+pub struct SelectionArgs {
+    ///Regex to match process cmdline against
+    #[arg(value_parser = Regex::new)]
+    pub regex: Option<Regex>,
+
+    ///Include children of matched processes, even if they don't match
+    #[arg(short = 'c', long)]
+    pub match_children: bool,
+
+    ///Match processes with these pids. Can be a comma-separated list or given more than once.
+    #[arg(short = 'p', long = "pid", value_name = "PID", value_delimiter = ',')]
+    pub pids: Vec<i32>,
+
+    ///Match processes whose comm (the executable name in /proc/<pid>/stat) matches <REGEX>
+    #[arg(long, value_name = "REGEX", value_parser = Regex::new)]
+    pub comm: Option<Regex>,
+
+    ///Match processes whose executable path matches <REGEX>
+    #[arg(long, value_name = "REGEX", value_parser = Regex::new)]
+    pub exe: Option<Regex>,
+
+    ///Match processes owned by <USER>, which is a user name or a uid
+    #[arg(short = 'u', long, value_parser = parse_user)]
+    pub user: Option<u32>,
+
+    ///Match the children of the process with pid <PPID>
+    #[arg(long)]
+    pub ppid: Option<i32>,
+
+    ///Match processes in the cgroup v2 <PATH> (as in /proc/<pid>/cgroup, e.g.
+    ////system.slice/nginx.service) or in one of its descendants
+    #[arg(long, value_name = "PATH")]
+    pub cgroup: Option<String>,
+
+    ///Match processes that match <EXPR>, a combination of conditions like "user=bob and
+    ///(comm=nginx or not ppid=1)". A condition is KEY=VALUE, where KEY is one of cmdline, comm,
+    ///exe, pid, ppid, user, or cgroup, or else a regex for the cmdline. See the README for the
+    ///details.
+    #[arg(long, value_name = "EXPR", value_parser = Selector::from_str)]
+    pub select: Option<Selector>,
+
+    ///Match processes that match any of REGEX, --pid, --comm, --exe, --user, --ppid, --cgroup, and
+    ///--select, instead of all of them
+    #[arg(long)]
+    pub any: bool,
+
+    ///Leave out processes that match <EXPR>, even if they are children of matched processes. This
+    ///is a regex for the cmdline, or a combination of conditions as in --select, e.g. "comm=grep or
+    ///pid=1234". Can be given more than once.
+    #[arg(short = 'x', long, value_name = "EXPR", value_parser = Selector::from_str)]
+    pub exclude: Vec<Selector>,
+
+    ///Read processes from <DIR> instead of /proc, e.g. a container's procfs mounted elsewhere or a
+    ///copy of one. Each <DIR>/<pid> needs at least stat, cmdline, exe (as a symlink), and smaps.
+    #[arg(long, value_name = "DIR", default_value = PROC_ROOT)]
+    pub proc_root: PathBuf,
+
+    ///Match the process for this program.
+    #[arg(short = 's', long, default_value_t = false)]
+    pub match_self: bool,
+}
+
+impl SelectionArgs {
+    pub fn selection(&self) -> Selection {
+        let mut selectors: Vec<Selector> =
+            self.regex.iter().cloned().map(Selector::Cmdline).collect();
+        if !self.pids.is_empty() {
+            selectors.push(Selector::Any(
+                self.pids.iter().copied().map(Selector::Pid).collect(),
+            ));
+        }
+        selectors.extend(self.comm.clone().map(Selector::Comm));
+        selectors.extend(self.exe.clone().map(Selector::Exe));
+        selectors.extend(self.user.map(Selector::Uid));
+        selectors.extend(self.ppid.map(Selector::Ppid));
+        selectors.extend(self.cgroup.clone().map(Selector::Cgroup));
+        selectors.extend(self.select.clone());
+        let include = match (selectors.is_empty(), self.any) {
+            (true, _) => None,
+            (false, true) => Some(Selector::Any(selectors)),
+            (false, false) => Some(Selector::All(selectors)),
+        };
+        let exclude = (!self.exclude.is_empty()).then(|| Selector::Any(self.exclude.clone()));
+        Selection {
+            include,
+            match_children: self.match_children,
+            exclude,
+            match_self: self.match_self,
+        }
+    }
+}
+
+/// What to do about the errors that come up while reading processes.
+#[derive(Args, Clone, Debug)]
+pub struct ErrorArgs {
+    ///Fail if permission is denied to read a process's info. Default behavior is to skip the
+    ///process and continue running.
+    #[arg(short, long)]
+    pub fail_on_noperm: bool,
+
+    ///What to do about a kind of error: skip, warn, or fail. KIND is one of permission-denied,
+    ///not-found, proc, missing-pss, unclassifiable, or missing-parent. Can be given more than once.
+    ///Takes precedence over --fail-on-noperm.
+    #[arg(short = 'e', long, value_name = "KIND=ACTION", value_parser = parse_on_error)]
+    pub on_error: Vec<(ErrorKind, Action)>,
+}
+
+impl ErrorArgs {
+    pub fn policy(&self) -> ErrorPolicy {
+        let mut policy = ErrorPolicy::default();
+        if self.fail_on_noperm {
+            policy.set(ErrorKind::PermissionDenied, Action::Fail);
+        }
+        for &(kind, action) in &self.on_error {
+            policy.set(kind, action);
+        }
+        policy
+    }
+}
+
+pub fn parse_user(s: &str) -> Result<u32, String> {
+    if let Ok(uid) = s.parse() {
+        return Ok(uid);
+    }
+    match uid_from_user(s) {
+        Ok(Some(uid)) => Ok(uid),
+        Ok(None) => Err(format!("no user named \"{s}\"")),
+        Err(e) => Err(format!("couldn't read /etc/passwd: {e}")),
+    }
+}
+
+/// For a --rules argument, whose help is up to each program.
+pub fn parse_rules(s: &str) -> Result<Rules, String> {
+    Rules::load(Path::new(s)).map_err(|e| format!("couldn't load rules: {e}"))
+}
+
+pub fn parse_on_error(s: &str) -> Result<(ErrorKind, Action), String> {
+    let (kind, action) = s
+        .split_once('=')
+        .ok_or_else(|| format!("expected KIND=ACTION, got \"{s}\""))?;
+    Ok((kind.parse()?, action.parse()?))
+}
@@ -0,0 +1,74 @@
+/* Copyright 2025 Andrew Riachi
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, version 3 only.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Writes a single HTML file with every recorded message and the script to chart them, so that
+//! the report can be opened offline and attached to a bug.
+
+use crate::{Message, PALETTE};
+use serde::Serialize;
+use smaps_profiler::Metric;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+const TEMPLATE: &str = include_str!("report.html");
+
+#[derive(Serialize)]
+struct ReportData<'a> {
+    metric: Metric,
+    metric_name: &'static str,
+    categories: &'a [&'a str],
+    palette: &'a [&'a str],
+    swap: Option<SwapLayer>,
+    messages: &'a [Message],
+}
+
+/// The swap that is stacked on top of the other categories, as in the graph
+#[derive(Serialize)]
+struct SwapLayer {
+    label: &'static str,
+    /// The field of `SimpleMemory` to take it from
+    field: &'static str,
+}
+
+pub fn save(
+    path: &Path,
+    messages: &[Message],
+    metric: Metric,
+    categories: &[&str],
+) -> io::Result<()> {
+    let swap = match metric {
+        Metric::Swap | Metric::SwapPss => None,
+        Metric::Pss => Some(SwapLayer {
+            label: "Swapped Out (SwapPss)",
+            field: "swap_pss",
+        }),
+        _ => Some(SwapLayer {
+            label: "Swapped Out (Swap)",
+            field: "swap",
+        }),
+    };
+    let data = ReportData {
+        metric,
+        metric_name: metric.long_name(),
+        categories,
+        palette: &PALETTE,
+        swap,
+        messages,
+    };
+    // a cmdline could contain "</script>"
+    let json = serde_json::to_string(&data)?.replace("</", "<\\/");
+    fs::write(path, TEMPLATE.replace("/*DATA*/null", &json))
+}
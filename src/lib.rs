@@ -13,24 +13,43 @@
  * along with this program. If not, see <https://www.gnu.org/licenses/>.
  */
 
+pub mod cli;
+
 use derive_more::{Add, Sum};
 use log::warn;
-use procfs::process::{self, MMapPath::*};
-pub use procfs::process::{MMPermissions, Process};
-use procfs::ProcError::{NotFound, PermissionDenied};
+use procfs::process::{self, MMapPath, MMapPath::*};
+pub use procfs::process::{MMPermissions, MemoryMap, Process, VmFlags};
+use procfs::ProcError::{self, NotFound, PermissionDenied};
 use procfs::ProcResult;
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 use std::collections::{HashMap, HashSet};
+use std::fmt;
 use std::hash::Hash;
 use std::iter;
 use std::ops::Add;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::SystemTime;
+
+/// Where procfs is normally mounted.
+pub const PROC_ROOT: &str = "/proc";
+
+/// Where the cgroup v2 hierarchy is normally mounted. On systems with both versions, it can be at
+/// /sys/fs/cgroup/unified instead.
+pub const CGROUP_ROOT: &str = "/sys/fs/cgroup";
 
 #[derive(Debug)]
 pub struct Proc {
     pub pid: i32,
     pub ppid: i32,
     pub cmdline: String,
+    /// The executable name from /proc/pid/stat, truncated by the kernel to 15 characters
+    pub comm: String,
+    /// The owner of /proc/pid, which is the process's effective uid
+    pub uid: u32,
+    /// The path of the process's cgroup in the cgroup v2 hierarchy, or `None` if
+    /// /proc/pid/cgroup couldn't be read or has no v2 entry
+    pub cgroup: Option<String>,
     pub faults: Faults,
     pub process: Process,
 }
@@ -39,26 +58,41 @@ pub struct Proc {
 // function delegation for trait impls has been proposed in rust-lang/rfcs/#3530.
 // property delegation as in Kotlin would be nice.
 impl Proc {
-    fn try_from_process(process: Process, convert_self: bool) -> ProcResult<Option<Proc>> {
+    fn try_from_process(process: Process) -> ProcResult<Proc> {
         let stat = process.stat()?;
-        let pid = stat.pid;
-        let me: i32 = std::process::id().try_into().unwrap();
-        if !convert_self && pid == me {
-            return Ok(None);
-        }
-        Ok(Some(Proc {
-            pid,
+        Ok(Proc {
+            pid: stat.pid,
             ppid: stat.ppid,
             cmdline: process.cmdline()?.join(" "),
+            uid: process.uid()?,
+            comm: stat.comm,
+            cgroup: read_cgroup(&process),
             faults: Faults {
                 minor: stat.minflt,
                 major: stat.majflt,
             },
             process,
-        }))
+        })
     }
 }
 
+fn read_cgroup(process: &Process) -> Option<String> {
+    let cgroups = process.cgroups().ok()?;
+    cgroups
+        .0
+        .into_iter()
+        .find(|c| c.hierarchy == 0 && c.controllers.is_empty())
+        .map(|c| c.pathname)
+}
+
+/// Whether `cgroup` is `ancestor` or one of its descendants.
+fn cgroup_is_within(cgroup: &str, ancestor: &str) -> bool {
+    let ancestor = ancestor.trim_end_matches('/');
+    cgroup
+        .strip_prefix(ancestor)
+        .is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+}
+
 #[derive(Debug)]
 struct ProcNode {
     proc: Proc,
@@ -74,17 +108,67 @@ impl From<Proc> for ProcNode {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ProcListing {
     pub pid: i32,
     pub ppid: i32,
     pub cmdline: String,
+    /// The executable name from /proc/pid/stat, truncated by the kernel to 15 characters
+    #[serde(default)]
+    pub comm: String,
+    #[serde(default)]
+    pub cgroup: Option<String>,
     pub faults: Faults,
     pub memory_ext: MemoryExt,
+    /// Every mapping that was added to `memory_ext`, if `SmapsOptions::keep_vmas` was set.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub vmas: Option<Vec<Vma>>,
+}
+
+/// One mapping from /proc/pid/smaps, as it was before it was added to its category.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Vma {
+    pub start: u64,
+    pub end: u64,
+    #[serde(with = "perms_string")]
+    pub perms: MMPermissions,
+    pub offset: u64,
+    /// (major, minor)
+    pub dev: (i32, i32),
+    pub inode: u64,
+    /// As it appears in /proc/pid/maps
+    pub pathname: String,
+    #[serde(with = "vm_flags_string")]
+    pub vm_flags: VmFlags,
+    /// Where the mapping was added in `MemoryExt`. File-backed mappings are not masked.
+    pub category: MemCategory,
+    pub stats: MemStats,
+}
+
+impl Vma {
+    fn new(map: &MemoryMap, category: MemCategory, stats: MemStats) -> Vma {
+        Vma {
+            start: map.address.0,
+            end: map.address.1,
+            perms: map.perms,
+            offset: map.offset,
+            dev: map.dev,
+            inode: map.inode,
+            pathname: pathname_to_string(&map.pathname),
+            vm_flags: map.extension.vm_flags,
+            category,
+            stats,
+        }
+    }
+
+    /// The size of the address range, which is usually more than what is resident.
+    pub fn size(&self) -> u64 {
+        self.end - self.start
+    }
 }
 
 ///Almost the same as procfs::process::MMapPath. A dictionary key that will allow us to aggregate the maps of a process by their (Path, Permissions).
-#[derive(Clone, Debug, PartialEq, Eq)]
+#[derive(Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub enum MemCategory {
     File(MaskedFileMapping),
     Heap,
@@ -95,13 +179,28 @@ pub enum MemCategory {
     Vsyscall,
     Anonymous,
     Vsys,
+    /// An anonymous mapping that was named with `PR_SET_VMA_ANON_NAME`, shown as "[anon:NAME]"
+    NamedAnon(String),
+    /// A shared anonymous mapping, shown as "[anon_shmem:NAME]" if it was named or else as
+    /// "/dev/zero (deleted)"
+    AnonShmem(String),
+    /// A file created with `memfd_create`, by its name
+    Memfd(String),
+    /// A mapping backed by hugetlbfs pages (with the ht VmFlag), by its path
+    Hugetlb(String),
+    /// A file-backed mapping whose file has been deleted
+    Deleted(MaskedFileMapping),
+    /// A mapping that matched a `Rule`, by the rule's category
+    Custom(String),
     Other(String),
+    Unclassified,
 }
 
-#[derive(Clone, Debug, Eq, PartialEq, Hash)]
+#[derive(Clone, Debug, Eq, PartialEq, Hash, Serialize, Deserialize)]
 pub struct FileMapping {
     pub is_self: bool,
     pub path: PathBuf,
+    #[serde(with = "perms_string")]
     pub perms: MMPermissions,
 }
 
@@ -144,10 +243,39 @@ impl FMask {
     }
 }
 
-#[derive(Clone, Debug, Eq, PartialEq, Hash)]
+/// Parses any combination of the characters "bfrwxsp": "b" keeps whether the file is the
+/// process's own binary, "f" keeps the path, and the rest keep those permissions. The empty
+/// string masks out everything.
+impl FromStr for FMask {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<FMask, String> {
+        let mut mask = FMask::new(false, false, MMPermissions::NONE);
+        for c in s.chars() {
+            match c {
+                'b' => mask.is_self = true,
+                'f' => mask.path = true,
+                'r' => mask.perms |= MMPermissions::READ,
+                'w' => mask.perms |= MMPermissions::WRITE,
+                'x' => mask.perms |= MMPermissions::EXECUTE,
+                's' => mask.perms |= MMPermissions::SHARED,
+                'p' => mask.perms |= MMPermissions::PRIVATE,
+                _ => {
+                    return Err(format!(
+                        "invalid mask \"{s}\" (expected any of \"bfrwxsp\")"
+                    ))
+                }
+            }
+        }
+        Ok(mask)
+    }
+}
+
+#[derive(Clone, Debug, Eq, PartialEq, Hash, Serialize, Deserialize)]
 pub struct MaskedFileMapping {
     pub is_self: Option<bool>,
     pub path: Option<PathBuf>,
+    #[serde(with = "perms_string")]
     pub masked_perms: MMPermissions,
 }
 
@@ -171,18 +299,466 @@ impl From<FileMapping> for MaskedFileMapping {
     }
 }
 
-#[derive(Debug, Clone, Default)]
+/// (De)serializes `MMPermissions` as a string like "r-x-p", with one character for each of "rwxsp"
+/// that is either that letter or '-'.
+pub mod perms_string {
+    use super::MMPermissions;
+    use serde::{de::Error, Deserialize, Deserializer, Serializer};
+
+    const FLAGS: [(char, MMPermissions); 5] = [
+        ('r', MMPermissions::READ),
+        ('w', MMPermissions::WRITE),
+        ('x', MMPermissions::EXECUTE),
+        ('s', MMPermissions::SHARED),
+        ('p', MMPermissions::PRIVATE),
+    ];
+
+    /// Formats the permissions the way they are serialized, e.g. "r-x-p".
+    pub fn to_string(perms: MMPermissions) -> String {
+        to_masked_string(perms, MMPermissions::all())
+    }
+
+    /// Like `to_string`, but only with the characters of the permissions in `mask`, e.g. "x" or "-"
+    /// for a file-backed mapping that was masked with `MMPermissions::EXECUTE`.
+    pub fn to_masked_string(perms: MMPermissions, mask: MMPermissions) -> String {
+        FLAGS
+            .iter()
+            .filter(|(_, flag)| mask.contains(*flag))
+            .map(|(c, flag)| if perms.contains(*flag) { *c } else { '-' })
+            .collect()
+    }
+
+    pub fn serialize<S: Serializer>(
+        perms: &MMPermissions,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&to_string(*perms))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<MMPermissions, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        if s.chars().count() != FLAGS.len() {
+            return Err(D::Error::custom(format!(
+                "expected 5 characters, got \"{s}\""
+            )));
+        }
+        let mut perms = MMPermissions::NONE;
+        for (c, (expected, flag)) in s.chars().zip(FLAGS) {
+            match c {
+                '-' => {}
+                c if c == expected => perms |= flag,
+                c => {
+                    return Err(D::Error::custom(format!(
+                        "expected '{expected}' or '-', got '{c}'"
+                    )))
+                }
+            }
+        }
+        Ok(perms)
+    }
+}
+
+/// (De)serializes `VmFlags` as a string of two-letter abbreviations separated by spaces, like the
+/// VmFlags line in /proc/pid/smaps.
+pub mod vm_flags_string {
+    use super::VmFlags;
+    use serde::{de::Error, Deserialize, Deserializer, Serializer};
+
+    const FLAGS: [(&str, VmFlags); 32] = [
+        ("rd", VmFlags::RD),
+        ("wr", VmFlags::WR),
+        ("ex", VmFlags::EX),
+        ("sh", VmFlags::SH),
+        ("mr", VmFlags::MR),
+        ("mw", VmFlags::MW),
+        ("me", VmFlags::ME),
+        ("ms", VmFlags::MS),
+        ("gd", VmFlags::GD),
+        ("pf", VmFlags::PF),
+        ("dw", VmFlags::DW),
+        ("lo", VmFlags::LO),
+        ("io", VmFlags::IO),
+        ("sr", VmFlags::SR),
+        ("rr", VmFlags::RR),
+        ("dc", VmFlags::DC),
+        ("de", VmFlags::DE),
+        ("ac", VmFlags::AC),
+        ("nr", VmFlags::NR),
+        ("ht", VmFlags::HT),
+        ("sf", VmFlags::SF),
+        ("nl", VmFlags::NL),
+        ("ar", VmFlags::AR),
+        ("wf", VmFlags::WF),
+        ("dd", VmFlags::DD),
+        ("sd", VmFlags::SD),
+        ("mm", VmFlags::MM),
+        ("hg", VmFlags::HG),
+        ("nh", VmFlags::NH),
+        ("mg", VmFlags::MG),
+        ("um", VmFlags::UM),
+        ("uw", VmFlags::UW),
+    ];
+
+    /// Formats the flags the way the kernel does.
+    pub fn to_string(flags: VmFlags) -> String {
+        FLAGS
+            .iter()
+            .filter(|(_, flag)| flags.contains(*flag))
+            .map(|(name, _)| *name)
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+
+    pub fn serialize<S: Serializer>(flags: &VmFlags, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&to_string(*flags))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<VmFlags, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        let mut flags = VmFlags::NONE;
+        for name in s.split_whitespace() {
+            match FLAGS.iter().find(|(n, _)| *n == name) {
+                Some((_, flag)) => flags |= *flag,
+                None => return Err(D::Error::custom(format!("unknown VmFlag \"{name}\""))),
+            }
+        }
+        Ok(flags)
+    }
+}
+
+/// (De)serializes a `HashMap` as a list of `{"key": ..., "value": ...}` objects, for maps whose
+/// keys can't be object keys in formats like JSON.
+mod map_as_list {
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+    use std::collections::HashMap;
+    use std::hash::Hash;
+
+    #[derive(Serialize)]
+    struct EntryRef<'a, K, V> {
+        key: &'a K,
+        value: &'a V,
+    }
+
+    #[derive(Deserialize)]
+    struct Entry<K, V> {
+        key: K,
+        value: V,
+    }
+
+    pub fn serialize<K, V, S>(map: &HashMap<K, V>, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        K: Serialize,
+        V: Serialize,
+        S: Serializer,
+    {
+        serializer.collect_seq(map.iter().map(|(key, value)| EntryRef { key, value }))
+    }
+
+    pub fn deserialize<'de, K, V, D>(deserializer: D) -> Result<HashMap<K, V>, D::Error>
+    where
+        K: Deserialize<'de> + Eq + Hash,
+        V: Deserialize<'de>,
+        D: Deserializer<'de>,
+    {
+        let entries = Vec::<Entry<K, V>>::deserialize(deserializer)?;
+        Ok(entries.into_iter().map(|e| (e.key, e.value)).collect())
+    }
+}
+
+/// The counters that /proc/pid/smaps reports for a mapping, in bytes. The huge page counters are
+/// missing from snapshots that were saved by older versions, so they default to 0.
+#[derive(Add, Sum, Debug, Copy, Clone, Serialize, Deserialize, Default, PartialEq, Eq)]
+pub struct MemStats {
+    pub rss: u64,
+    pub pss: u64,
+    pub shared_clean: u64,
+    pub shared_dirty: u64,
+    pub private_clean: u64,
+    pub private_dirty: u64,
+    pub referenced: u64,
+    pub anonymous: u64,
+    pub lazy_free: u64,
+    pub swap: u64,
+    pub swap_pss: u64,
+    pub locked: u64,
+    /// Anonymous memory backed by transparent huge pages
+    #[serde(default)]
+    pub anon_huge_pages: u64,
+    /// Shared memory mapped with transparent huge pages
+    #[serde(default)]
+    pub shmem_pmd_mapped: u64,
+    /// File-backed memory mapped with transparent huge pages
+    #[serde(default)]
+    pub file_pmd_mapped: u64,
+    /// hugetlbfs pages, which are not counted in Rss or Pss
+    #[serde(default)]
+    pub shared_hugetlb: u64,
+    #[serde(default)]
+    pub private_hugetlb: u64,
+    /// The size of the mappings that have the ht VmFlag, meaning that they use hugetlbfs pages.
+    /// This is not in smaps_rollup, which has no VmFlags.
+    #[serde(default)]
+    pub ht_size: u64,
+}
+
+impl MemStats {
+    fn from_smaps(map: &HashMap<String, u64>) -> MemStats {
+        let get = |key: &str| map.get(key).copied().unwrap_or(0);
+        MemStats {
+            rss: get("Rss"),
+            pss: get("Pss"),
+            shared_clean: get("Shared_Clean"),
+            shared_dirty: get("Shared_Dirty"),
+            private_clean: get("Private_Clean"),
+            private_dirty: get("Private_Dirty"),
+            referenced: get("Referenced"),
+            anonymous: get("Anonymous"),
+            lazy_free: get("LazyFree"),
+            swap: get("Swap"),
+            swap_pss: get("SwapPss"),
+            locked: get("Locked"),
+            anon_huge_pages: get("AnonHugePages"),
+            shmem_pmd_mapped: get("ShmemPmdMapped"),
+            file_pmd_mapped: get("FilePmdMapped"),
+            shared_hugetlb: get("Shared_Hugetlb"),
+            private_hugetlb: get("Private_Hugetlb"),
+            ht_size: 0,
+        }
+    }
+
+    fn from_map(map: &MemoryMap) -> MemStats {
+        let (start, end) = map.address;
+        MemStats {
+            ht_size: if map.extension.vm_flags.contains(VmFlags::HT) {
+                end - start
+            } else {
+                0
+            },
+            ..MemStats::from_smaps(&map.extension.map)
+        }
+    }
+
+    pub fn get(&self, metric: Metric) -> u64 {
+        match metric {
+            Metric::Rss => self.rss,
+            Metric::Pss => self.pss,
+            Metric::SharedClean => self.shared_clean,
+            Metric::SharedDirty => self.shared_dirty,
+            Metric::PrivateClean => self.private_clean,
+            Metric::PrivateDirty => self.private_dirty,
+            Metric::Referenced => self.referenced,
+            Metric::Anonymous => self.anonymous,
+            Metric::LazyFree => self.lazy_free,
+            Metric::Swap => self.swap,
+            Metric::SwapPss => self.swap_pss,
+            Metric::Locked => self.locked,
+            Metric::AnonHugePages => self.anon_huge_pages,
+            Metric::ShmemPmdMapped => self.shmem_pmd_mapped,
+            Metric::FilePmdMapped => self.file_pmd_mapped,
+            Metric::SharedHugetlb => self.shared_hugetlb,
+            Metric::PrivateHugetlb => self.private_hugetlb,
+            Metric::HtSize => self.ht_size,
+            Metric::Uss => self.uss(),
+        }
+    }
+
+    /// The unique set size: the memory that only this mapping or process has, which is what
+    /// would be freed if it went away. Memory that is swapped out is not included.
+    pub fn uss(&self) -> u64 {
+        self.private_clean + self.private_dirty
+    }
+}
+
+impl Add<&MemStats> for MemStats {
+    type Output = MemStats;
+
+    fn add(self, rhs: &MemStats) -> MemStats {
+        self + *rhs
+    }
+}
+
+/// Selects one of the counters in `MemStats`, or `Uss`, which is computed from them. Parses from and
+/// displays as the field name used in /proc/pid/smaps (case-insensitive when parsing), except for
+/// `HtSize` and `Uss`, which aren't in smaps.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub enum Metric {
+    Rss,
+    #[default]
+    Pss,
+    SharedClean,
+    SharedDirty,
+    PrivateClean,
+    PrivateDirty,
+    Referenced,
+    Anonymous,
+    LazyFree,
+    Swap,
+    SwapPss,
+    Locked,
+    AnonHugePages,
+    ShmemPmdMapped,
+    FilePmdMapped,
+    SharedHugetlb,
+    PrivateHugetlb,
+    HtSize,
+    /// Private_Clean + Private_Dirty
+    Uss,
+}
+
+impl Metric {
+    pub const ALL: [Metric; 19] = [
+        Metric::Rss,
+        Metric::Pss,
+        Metric::SharedClean,
+        Metric::SharedDirty,
+        Metric::PrivateClean,
+        Metric::PrivateDirty,
+        Metric::Referenced,
+        Metric::Anonymous,
+        Metric::LazyFree,
+        Metric::Swap,
+        Metric::SwapPss,
+        Metric::Locked,
+        Metric::AnonHugePages,
+        Metric::ShmemPmdMapped,
+        Metric::FilePmdMapped,
+        Metric::SharedHugetlb,
+        Metric::PrivateHugetlb,
+        Metric::HtSize,
+        Metric::Uss,
+    ];
+
+    /// The name of the field in /proc/pid/smaps, or "Ht_Size" or "Uss", which aren't in smaps
+    pub fn smaps_name(&self) -> &'static str {
+        match self {
+            Metric::Rss => "Rss",
+            Metric::Pss => "Pss",
+            Metric::SharedClean => "Shared_Clean",
+            Metric::SharedDirty => "Shared_Dirty",
+            Metric::PrivateClean => "Private_Clean",
+            Metric::PrivateDirty => "Private_Dirty",
+            Metric::Referenced => "Referenced",
+            Metric::Anonymous => "Anonymous",
+            Metric::LazyFree => "LazyFree",
+            Metric::Swap => "Swap",
+            Metric::SwapPss => "SwapPss",
+            Metric::Locked => "Locked",
+            Metric::AnonHugePages => "AnonHugePages",
+            Metric::ShmemPmdMapped => "ShmemPmdMapped",
+            Metric::FilePmdMapped => "FilePmdMapped",
+            Metric::SharedHugetlb => "Shared_Hugetlb",
+            Metric::PrivateHugetlb => "Private_Hugetlb",
+            Metric::HtSize => "Ht_Size",
+            Metric::Uss => "Uss",
+        }
+    }
+
+    /// A human-readable name, suitable for axis labels and table headers
+    pub fn long_name(&self) -> &'static str {
+        match self {
+            Metric::Rss => "Resident Set Size",
+            Metric::Pss => "Proportional Set Size",
+            Metric::SharedClean => "Shared Clean",
+            Metric::SharedDirty => "Shared Dirty",
+            Metric::PrivateClean => "Private Clean",
+            Metric::PrivateDirty => "Private Dirty",
+            Metric::Referenced => "Referenced",
+            Metric::Anonymous => "Anonymous",
+            Metric::LazyFree => "Lazy Free",
+            Metric::Swap => "Swap",
+            Metric::SwapPss => "Proportional Swap",
+            Metric::Locked => "Locked",
+            Metric::AnonHugePages => "Anonymous Huge Pages",
+            Metric::ShmemPmdMapped => "Shared Memory Huge Pages",
+            Metric::FilePmdMapped => "File Huge Pages",
+            Metric::SharedHugetlb => "Shared Hugetlb",
+            Metric::PrivateHugetlb => "Private Hugetlb",
+            Metric::HtSize => "Size of Hugetlb Mappings",
+            Metric::Uss => "Unique Set Size",
+        }
+    }
+}
+
+impl fmt::Display for Metric {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.smaps_name())
+    }
+}
+
+impl FromStr for Metric {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Metric, String> {
+        Metric::ALL
+            .into_iter()
+            .find(|m| m.smaps_name().eq_ignore_ascii_case(s))
+            .ok_or_else(|| {
+                let names: Vec<_> = Metric::ALL.iter().map(|m| m.smaps_name()).collect();
+                format!(
+                    "unknown metric \"{s}\" (expected one of {})",
+                    names.join(", ")
+                )
+            })
+    }
+}
+
+impl Serialize for Metric {
+    fn serialize<S: serde::Serializer>(
+        &self,
+        serializer: S,
+    ) -> std::result::Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.smaps_name())
+    }
+}
+
+impl<'de> Deserialize<'de> for Metric {
+    fn deserialize<D: serde::Deserializer<'de>>(
+        deserializer: D,
+    ) -> std::result::Result<Metric, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(serde::de::Error::custom)
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
 pub struct MemoryExt {
-    pub stack_pss: u64,
-    pub heap_pss: u64,
-    pub thread_stack_pss: u64,
-    pub file_map: HashMap<FileMapping, u64>,
-    pub anon_map_pss: u64,
-    pub vdso_pss: u64,
-    pub vvar_pss: u64,
-    pub vsyscall_pss: u64,
-    pub vsys_pss: u64,
-    pub other_map: HashMap<String, u64>,
+    pub stack: MemStats,
+    pub heap: MemStats,
+    pub thread_stack: MemStats,
+    #[serde(with = "map_as_list")]
+    pub file_map: HashMap<FileMapping, MemStats>,
+    pub anon_map: MemStats,
+    pub vdso: MemStats,
+    pub vvar: MemStats,
+    pub vsyscall: MemStats,
+    pub vsys: MemStats,
+    /// By the name in "[anon:NAME]"
+    #[serde(default)]
+    pub named_anon_map: HashMap<String, MemStats>,
+    /// By the name in "[anon_shmem:NAME]". Unnamed shared anonymous mappings, which show up as
+    /// "/dev/zero (deleted)", have an empty name.
+    #[serde(default)]
+    pub anon_shmem_map: HashMap<String, MemStats>,
+    /// By the name in "/memfd:NAME (deleted)"
+    #[serde(default)]
+    pub memfd_map: HashMap<String, MemStats>,
+    /// By the path without " (deleted)". Private and shared mappings with `MAP_HUGETLB` show up as
+    /// "/anon_hugepage".
+    #[serde(default)]
+    pub hugetlb_map: HashMap<String, MemStats>,
+    /// File-backed mappings whose file has been deleted, by the path without " (deleted)"
+    #[serde(default, with = "map_as_list")]
+    pub deleted_map: HashMap<FileMapping, MemStats>,
+    /// Mappings that matched a `Rule`, by the rule's category
+    #[serde(default)]
+    pub custom_map: HashMap<String, MemStats>,
+    pub other_map: HashMap<String, MemStats>,
+    /// Memory that is known to belong to the process but was not attributed to any category, e.g.
+    /// because it was read from /proc/pid/smaps_rollup.
+    pub unclassified: MemStats,
 }
 
 impl MemoryExt {
@@ -194,61 +770,133 @@ impl MemoryExt {
     /// "store separate entries for distinct values of this field," while setting it to `false` means, "store
     /// distinct values of this field in the same entry." The `perms` parameter works the same way, but as a
     /// bitflag, so you can choose particular permissions you care about making a distinction on.
-    pub fn aggregate_file_maps(&self, mask: &FMask) -> HashMap<MaskedFileMapping, u64> {
-        let capacity = match (mask.is_self, mask.path, mask.perms) {
-            (_, true, _) => self.file_map.len(),
-            (s, false, p) => 1 << (num_bits_on(p.bits()) + s as u8),
-        };
-        let mut ret = HashMap::with_capacity(capacity);
-        for (f, pss) in self.file_map.iter() {
-            add_at(&mut ret, mask.apply(f), pss);
-        }
-        ret
+    pub fn aggregate_file_maps(&self, mask: &FMask) -> HashMap<MaskedFileMapping, MemStats> {
+        aggregate(&self.file_map, mask)
+    }
+
+    /// Like `aggregate_file_maps`, but for the mappings of deleted files.
+    pub fn aggregate_deleted_maps(&self, mask: &FMask) -> HashMap<MaskedFileMapping, MemStats> {
+        aggregate(&self.deleted_map, mask)
     }
 
-    /// Returns an iterator over all of the memory categories and their pss stored in this struct, where
-    /// the table of file-backed mappings is aggregated as it is in `aggregate_file_maps`.
+    /// Returns an iterator over all of the memory categories and their stats stored in this struct,
+    /// where the table of file-backed mappings is aggregated as it is in `aggregate_file_maps`.
     pub fn iter_aggregate(
         &self,
         mask: &FMask,
-    ) -> impl Iterator<Item = (MemCategory, u64)> + use<'_> {
+    ) -> impl Iterator<Item = (MemCategory, MemStats)> + use<'_> {
         let MemoryExt {
-            stack_pss,
-            heap_pss,
-            thread_stack_pss,
+            stack,
+            heap,
+            thread_stack,
             file_map: _,
-            anon_map_pss,
-            vdso_pss,
-            vvar_pss,
-            vsyscall_pss,
-            vsys_pss,
+            anon_map,
+            vdso,
+            vvar,
+            vsyscall,
+            vsys,
+            named_anon_map,
+            anon_shmem_map,
+            memfd_map,
+            hugetlb_map,
+            deleted_map: _,
+            custom_map,
             other_map,
+            unclassified,
         } = self; // destructure self here so that I get a compiler error if fields change
-        iter::once((MemCategory::Stack, *stack_pss))
-            .chain(iter::once((MemCategory::Heap, *heap_pss)))
-            .chain(iter::once((MemCategory::TStack, *thread_stack_pss)))
-            .chain(iter::once((MemCategory::Anonymous, *anon_map_pss)))
-            .chain(iter::once((MemCategory::Vdso, *vdso_pss)))
-            .chain(iter::once((MemCategory::Vvar, *vvar_pss)))
-            .chain(iter::once((MemCategory::Vsyscall, *vsyscall_pss)))
-            .chain(iter::once((MemCategory::Vsys, *vsys_pss)))
+        iter::once((MemCategory::Stack, *stack))
+            .chain(iter::once((MemCategory::Heap, *heap)))
+            .chain(iter::once((MemCategory::TStack, *thread_stack)))
+            .chain(iter::once((MemCategory::Anonymous, *anon_map)))
+            .chain(iter::once((MemCategory::Vdso, *vdso)))
+            .chain(iter::once((MemCategory::Vvar, *vvar)))
+            .chain(iter::once((MemCategory::Vsyscall, *vsyscall)))
+            .chain(iter::once((MemCategory::Vsys, *vsys)))
             .chain(
                 self.aggregate_file_maps(mask)
                     .into_iter()
-                    .map(|(f, pss)| (MemCategory::File(f), pss)),
+                    .map(|(f, stats)| (MemCategory::File(f), stats)),
+            )
+            .chain(
+                self.aggregate_deleted_maps(mask)
+                    .into_iter()
+                    .map(|(f, stats)| (MemCategory::Deleted(f), stats)),
+            )
+            .chain(
+                named_anon_map
+                    .iter()
+                    .map(|(s, stats)| (MemCategory::NamedAnon(s.clone()), *stats)),
+            )
+            .chain(
+                anon_shmem_map
+                    .iter()
+                    .map(|(s, stats)| (MemCategory::AnonShmem(s.clone()), *stats)),
+            )
+            .chain(
+                memfd_map
+                    .iter()
+                    .map(|(s, stats)| (MemCategory::Memfd(s.clone()), *stats)),
+            )
+            .chain(
+                hugetlb_map
+                    .iter()
+                    .map(|(s, stats)| (MemCategory::Hugetlb(s.clone()), *stats)),
+            )
+            .chain(
+                custom_map
+                    .iter()
+                    .map(|(s, stats)| (MemCategory::Custom(s.clone()), *stats)),
             )
             .chain(
                 other_map
                     .iter()
-                    .map(|(s, pss)| (MemCategory::Other(s.clone()), *pss)),
+                    .map(|(s, stats)| (MemCategory::Other(s.clone()), *stats)),
             )
+            .chain(iter::once((MemCategory::Unclassified, *unclassified)))
     }
 
-    pub fn total(&self) -> u64 {
+    pub fn total(&self) -> MemStats {
         self.iter_aggregate(&FMask::new(true, true, MMPermissions::all()))
-            .map(|(_, pss)| pss)
+            .map(|(_, stats)| stats)
             .sum()
     }
+
+    /// Matches up the categories of `self` and `after`, where the tables of file-backed mappings
+    /// are aggregated as in `aggregate_file_maps`. A file-backed or other mapping that only one of
+    /// them has is new or gone, while the rest of the categories are always in both.
+    pub fn diff(&self, after: &MemoryExt, mask: &FMask) -> HashMap<MemCategory, Diff<MemStats>> {
+        diff_categories(Some(self), Some(after), mask)
+    }
+}
+
+fn diff_categories(
+    before: Option<&MemoryExt>,
+    after: Option<&MemoryExt>,
+    mask: &FMask,
+) -> HashMap<MemCategory, Diff<MemStats>> {
+    let mut diffs: HashMap<MemCategory, Diff<MemStats>> = HashMap::new();
+    for (cat, stats) in before.into_iter().flat_map(|m| m.iter_aggregate(mask)) {
+        diffs.entry(cat).or_default().before = Some(stats);
+    }
+    for (cat, stats) in after.into_iter().flat_map(|m| m.iter_aggregate(mask)) {
+        diffs.entry(cat).or_default().after = Some(stats);
+    }
+    diffs
+}
+
+fn aggregate(
+    file_map: &HashMap<FileMapping, MemStats>,
+    mask: &FMask,
+) -> HashMap<MaskedFileMapping, MemStats> {
+    let capacity = match (mask.is_self, mask.path, mask.perms) {
+        (_, true, _) => file_map.len(),
+        (s, false, p) => 1 << (num_bits_on(p.bits()) + s as u8),
+    };
+    let mut ret = HashMap::with_capacity(capacity);
+    for (f, stats) in file_map.iter() {
+        add_at(&mut ret, mask.apply(f), stats);
+    }
+    ret
 }
 
 pub fn add_maps<K, V, A>(mut lhs: HashMap<K, V>, rhs: &HashMap<K, A>) -> HashMap<K, V>
@@ -287,16 +935,23 @@ impl Add<&MemoryExt> for MemoryExt {
 
     fn add(self, rhs: &MemoryExt) -> MemoryExt {
         MemoryExt {
-            stack_pss: self.stack_pss + rhs.stack_pss,
-            heap_pss: self.heap_pss + rhs.heap_pss,
-            thread_stack_pss: self.thread_stack_pss + rhs.thread_stack_pss,
+            stack: self.stack + rhs.stack,
+            heap: self.heap + rhs.heap,
+            thread_stack: self.thread_stack + rhs.thread_stack,
             file_map: add_maps(self.file_map, &rhs.file_map),
-            anon_map_pss: self.anon_map_pss + rhs.anon_map_pss,
-            vdso_pss: self.vdso_pss + rhs.vdso_pss,
-            vvar_pss: self.vvar_pss + rhs.vvar_pss,
-            vsyscall_pss: self.vsyscall_pss + rhs.vvar_pss,
-            vsys_pss: self.vsys_pss + rhs.vsys_pss,
+            anon_map: self.anon_map + rhs.anon_map,
+            vdso: self.vdso + rhs.vdso,
+            vvar: self.vvar + rhs.vvar,
+            vsyscall: self.vsyscall + rhs.vsyscall,
+            vsys: self.vsys + rhs.vsys,
+            named_anon_map: add_maps(self.named_anon_map, &rhs.named_anon_map),
+            anon_shmem_map: add_maps(self.anon_shmem_map, &rhs.anon_shmem_map),
+            memfd_map: add_maps(self.memfd_map, &rhs.memfd_map),
+            hugetlb_map: add_maps(self.hugetlb_map, &rhs.hugetlb_map),
+            deleted_map: add_maps(self.deleted_map, &rhs.deleted_map),
+            custom_map: add_maps(self.custom_map, &rhs.custom_map),
             other_map: add_maps(self.other_map, &rhs.other_map),
+            unclassified: self.unclassified + rhs.unclassified,
         }
     }
 }
@@ -310,7 +965,7 @@ impl Add<&MemoryExt> for MemoryExt {
 }
 */
 
-#[derive(Add, Debug, Copy, Clone, Serialize, Default, Sum)]
+#[derive(Add, Debug, Copy, Clone, Serialize, Deserialize, Default, Sum)]
 pub struct Faults {
     pub minor: u64,
     pub major: u64,
@@ -322,28 +977,805 @@ impl Faults {
     }
 }
 
-fn filter_errors<T>(result: ProcResult<T>, fail_on_noperm: bool) -> Option<ProcResult<T>> {
-    match result {
-        Err(PermissionDenied(path)) => {
-            if fail_on_noperm {
-                Some(Err(PermissionDenied(path)))
-            } else {
+/// Formats a pathname the way it appears in /proc/pid/maps.
+pub fn pathname_to_string(pathname: &MMapPath) -> String {
+    match pathname {
+        Path(pathbuf) => pathbuf.display().to_string(),
+        Heap => "[heap]".to_string(),
+        Stack => "[stack]".to_string(),
+        TStack(tid) => format!("[stack:{tid}]"),
+        Vdso => "[vdso]".to_string(),
+        Vvar => "[vvar]".to_string(),
+        Vsyscall => "[vsyscall]".to_string(),
+        Rollup => "[rollup]".to_string(),
+        Anonymous => String::new(),
+        Vsys(key) => format!("/SYSV{key:08x}"),
+        Other(name) => format!("[{name}]"),
+    }
+}
+
+/// How the kernel marks the path of a mapping whose file has been deleted.
+const DELETED_SUFFIX: &str = " (deleted)";
+/// The path of a file created with `memfd_create` is this prefix followed by its name.
+const MEMFD_PREFIX: &str = "/memfd:";
+/// The path of a shared anonymous mapping that has no name.
+const SHARED_ANON_PATH: &str = "/dev/zero (deleted)";
+
+/// The kind of pathname a mapping has, for matching a `Rule`. These are the variants of `MMapPath`
+/// that show up in /proc/pid/smaps.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum PathKind {
+    File,
+    Heap,
+    Stack,
+    ThreadStack,
+    Vdso,
+    Vvar,
+    Vsyscall,
+    Anonymous,
+    SysvShm,
+    Other,
+}
+
+impl PathKind {
+    pub fn of(pathname: &MMapPath) -> Option<PathKind> {
+        match pathname {
+            Path(_) => Some(PathKind::File),
+            Heap => Some(PathKind::Heap),
+            Stack => Some(PathKind::Stack),
+            TStack(_) => Some(PathKind::ThreadStack),
+            Vdso => Some(PathKind::Vdso),
+            Vvar => Some(PathKind::Vvar),
+            Vsyscall => Some(PathKind::Vsyscall),
+            Anonymous => Some(PathKind::Anonymous),
+            Vsys(_) => Some(PathKind::SysvShm),
+            Other(_) => Some(PathKind::Other),
+            Rollup => None,
+        }
+    }
+}
+
+/// Permissions to match against, written like in /proc/pid/maps (e.g. "r-xp"), except that any
+/// character can be '?' to match either way.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PermsPattern {
+    /// The permissions that are checked
+    pub mask: MMPermissions,
+    /// What the checked permissions have to be
+    pub value: MMPermissions,
+}
+
+impl PermsPattern {
+    pub fn matches(&self, perms: MMPermissions) -> bool {
+        perms.intersection(self.mask) == self.value
+    }
+}
+
+impl FromStr for PermsPattern {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<PermsPattern, String> {
+        let chars: Vec<char> = s.chars().collect();
+        let &[r, w, x, sharing] = chars.as_slice() else {
+            return Err(format!("expected 4 characters like \"r-xp\", got \"{s}\""));
+        };
+        let mut pattern = PermsPattern {
+            mask: MMPermissions::NONE,
+            value: MMPermissions::NONE,
+        };
+        for (c, expected, flag) in [
+            (r, 'r', MMPermissions::READ),
+            (w, 'w', MMPermissions::WRITE),
+            (x, 'x', MMPermissions::EXECUTE),
+        ] {
+            match c {
+                '?' => {}
+                '-' => pattern.mask |= flag,
+                c if c == expected => {
+                    pattern.mask |= flag;
+                    pattern.value |= flag;
+                }
+                c => return Err(format!("expected '{expected}', '-', or '?', got '{c}'")),
+            }
+        }
+        let flag = match sharing {
+            '?' => return Ok(pattern),
+            'p' => MMPermissions::PRIVATE,
+            's' => MMPermissions::SHARED,
+            c => return Err(format!("expected 'p', 's', or '?', got '{c}'")),
+        };
+        pattern.mask |= MMPermissions::PRIVATE | MMPermissions::SHARED;
+        pattern.value |= flag;
+        Ok(pattern)
+    }
+}
+
+impl<'de> Deserialize<'de> for PermsPattern {
+    fn deserialize<D: serde::Deserializer<'de>>(
+        deserializer: D,
+    ) -> std::result::Result<Self, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(serde::de::Error::custom)
+    }
+}
+
+/// Deserializes an optional regex from a string.
+mod regex_string {
+    use serde::{de::Error, Deserialize, Deserializer};
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Option<regex::Regex>, D::Error> {
+        Option::<String>::deserialize(deserializer)?
+            .map(|s| regex::Regex::new(&s).map_err(D::Error::custom))
+            .transpose()
+    }
+}
+
+/// Rejects a blank category name, which would have no label or column name to show it by.
+fn category_name<'de, D: serde::Deserializer<'de>>(
+    deserializer: D,
+) -> std::result::Result<String, D::Error> {
+    let name = String::deserialize(deserializer)?;
+    if name.trim().is_empty() {
+        return Err(serde::de::Error::custom("category can't be empty"));
+    }
+    Ok(name)
+}
+
+/// Puts the mappings that match it in a category of their own. A mapping matches a rule if it
+/// passes every condition that the rule has.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Rule {
+    /// The name of the category, e.g. "JIT code"
+    #[serde(deserialize_with = "category_name")]
+    pub category: String,
+    /// Matches the pathname as it appears in /proc/pid/maps, e.g. "/usr/lib/libc.so.6", "[heap]",
+    /// or "[anon:v8]". Anonymous mappings have an empty pathname.
+    #[serde(default, with = "regex_string")]
+    pub path: Option<regex::Regex>,
+    #[serde(default)]
+    pub perms: Option<PermsPattern>,
+    #[serde(default)]
+    pub kind: Option<PathKind>,
+    /// Whether the mapping is of the process's own executable
+    #[serde(default)]
+    pub is_self: Option<bool>,
+}
+
+impl Rule {
+    fn matches(
+        &self,
+        path: &str,
+        kind: Option<PathKind>,
+        perms: MMPermissions,
+        is_self: bool,
+    ) -> bool {
+        self.path.as_ref().is_none_or(|re| re.is_match(path))
+            && self.perms.is_none_or(|p| p.matches(perms))
+            && self.kind.is_none_or(|k| kind == Some(k))
+            && self.is_self.is_none_or(|b| b == is_self)
+    }
+}
+
+/// User-defined categories, which take precedence over the built-in ones. They are read from a TOML
+/// file with a `[[rule]]` table for every `Rule`, and the first rule that a mapping matches wins.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Rules {
+    #[serde(default, rename = "rule")]
+    pub rules: Vec<Rule>,
+}
+
+impl Rules {
+    /// No rules, so every mapping gets a built-in category.
+    pub fn new() -> Rules {
+        Rules::default()
+    }
+
+    pub fn from_toml(s: &str) -> std::result::Result<Rules, toml::de::Error> {
+        toml::from_str(s)
+    }
+
+    pub fn load(path: &Path) -> std::io::Result<Rules> {
+        let s = std::fs::read_to_string(path)?;
+        Rules::from_toml(&s).map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))
+    }
+
+    /// The names of the categories, in the order that they first appear in the rules.
+    pub fn categories(&self) -> Vec<&str> {
+        let mut categories: Vec<&str> = Vec::new();
+        for rule in &self.rules {
+            if !categories.contains(&rule.category.as_str()) {
+                categories.push(&rule.category);
+            }
+        }
+        categories
+    }
+
+    /// The category of the first rule that the mapping matches, if any.
+    pub fn categorize(
+        &self,
+        pathname: &MMapPath,
+        perms: MMPermissions,
+        is_self: bool,
+    ) -> Option<&str> {
+        if self.rules.is_empty() {
+            return None;
+        }
+        let path = pathname_to_string(pathname);
+        let kind = PathKind::of(pathname);
+        self.rules
+            .iter()
+            .find(|rule| rule.matches(&path, kind, perms, is_self))
+            .map(|rule| rule.category.as_str())
+    }
+}
+
+/// Why `get_smaps` left a mapping out of `MemoryExt`, or counted it as 0.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum SkipReason {
+    /// The mapping has no Pss field and its Rss is 0, so its Pss was counted as 0.
+    NoPssZeroRss,
+    /// The mapping has neither a Pss nor an Rss field, so its Pss was counted as 0.
+    NoPssNoRss,
+    /// The mapping has no Pss field and its Rss is not 0, so it was left out entirely.
+    NoPss,
+    /// The mapping's pathname doesn't belong to any `MemCategory`, so it was left out entirely.
+    Unclassified,
+}
+
+#[derive(Clone, Debug)]
+pub struct SkippedMapping {
+    pub map: MemoryMap,
+    pub reason: SkipReason,
+}
+
+impl SkippedMapping {
+    pub fn new(map: MemoryMap, reason: SkipReason) -> SkippedMapping {
+        SkippedMapping { map, reason }
+    }
+}
+
+impl fmt::Display for SkipReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            SkipReason::NoPssZeroRss => "no Pss and Rss is 0, counted as 0",
+            SkipReason::NoPssNoRss => "no Pss or Rss, counted as 0",
+            SkipReason::NoPss => "no Pss but nonzero Rss, left out",
+            SkipReason::Unclassified => "unclassified, left out",
+        })
+    }
+}
+
+impl fmt::Display for SkippedMapping {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (start, end) = self.map.address;
+        write!(
+            f,
+            "{start:x}-{end:x} {} {}: {}",
+            self.map.perms.as_str(),
+            pathname_to_string(&self.map.pathname),
+            self.reason
+        )
+    }
+}
+
+/// The result of checking a process's categorized memory against /proc/pid/smaps_rollup.
+#[derive(Clone, Debug)]
+pub struct Reconciliation {
+    /// The kernel's sum of every mapping, from /proc/pid/smaps_rollup
+    pub rollup: MemStats,
+    /// The sum of every category in the process's `MemoryExt`
+    pub total: MemStats,
+    /// The number of mappings in /proc/pid/smaps
+    pub num_mappings: usize,
+    pub skipped: Vec<SkippedMapping>,
+}
+
+impl Reconciliation {
+    /// How much more memory smaps_rollup reports than the categories add up to.
+    pub fn discrepancy(&self, metric: Metric) -> i64 {
+        self.rollup.get(metric) as i64 - self.total.get(metric) as i64
+    }
+
+    /// The largest discrepancy that is explained by rounding alone. The kernel keeps Pss and
+    /// SwapPss in fractions of a page and truncates them to a whole kB when it writes each entry of
+    /// smaps, but only truncates once for smaps_rollup. So adding up smaps can come up short by
+    /// almost 1 kB per mapping. The other counters are whole pages and add up exactly.
+    pub fn rounding_bound(&self, metric: Metric) -> u64 {
+        match metric {
+            Metric::Pss | Metric::SwapPss => self.num_mappings as u64 * 1024,
+            _ => 0,
+        }
+    }
+
+    /// Whether the discrepancy is fully explained by rounding.
+    pub fn is_explained(&self, metric: Metric) -> bool {
+        let discrepancy = self.discrepancy(metric);
+        discrepancy >= 0 && discrepancy as u64 <= self.rounding_bound(metric)
+    }
+}
+
+/// Everything that can go wrong while reading processes.
+#[derive(Debug)]
+pub enum Error {
+    /// Reading a file in procfs failed.
+    Proc(ProcError),
+    /// A mapping has no Pss field, but its Rss is not 0, so its Pss can't be assumed to be 0.
+    MissingPss {
+        pid: i32,
+        cmdline: String,
+        map_type: String,
+        map: Box<MemoryMap>,
+    },
+    /// A mapping's pathname doesn't belong to any `MemCategory`, and its Rss is not 0.
+    Unclassifiable {
+        pid: i32,
+        cmdline: String,
+        map: Box<MemoryMap>,
+    },
+    /// A process's parent is not among the processes that were read, most likely because the
+    /// parent exited in between.
+    MissingParent { pid: i32, ppid: i32 },
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl Error {
+    pub fn kind(&self) -> ErrorKind {
+        match self {
+            Error::Proc(PermissionDenied(_)) => ErrorKind::PermissionDenied,
+            Error::Proc(NotFound(_)) => ErrorKind::NotFound,
+            Error::Proc(_) => ErrorKind::Proc,
+            Error::MissingPss { .. } => ErrorKind::MissingPss,
+            Error::Unclassifiable { .. } => ErrorKind::Unclassifiable,
+            Error::MissingParent { .. } => ErrorKind::MissingParent,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Proc(PermissionDenied(path)) => {
                 let path = path.as_ref().map_or("<empty path>", |p| {
                     p.as_path().to_str().unwrap_or("<non-unicode path>")
                 });
-                warn!("Permission denied when accessing {path}. Ignoring.");
+                write!(f, "Permission denied when accessing {path}")
+            }
+            Error::Proc(NotFound(Some(pathbuf))) => write!(
+                f,
+                "\"{}\" not found. The process may have exited before I could get its details",
+                pathbuf.display()
+            ),
+            Error::Proc(e) => write!(f, "{e}"),
+            Error::MissingPss {
+                pid,
+                cmdline,
+                map_type,
+                map,
+            } => write!(
+                f,
+                "PSS field not defined on {map_type}, and its RSS is not 0.\
+                \n  The process is {pid} {cmdline}\
+                \n  The map is {map:?}"
+            ),
+            Error::Unclassifiable { pid, cmdline, map } => write!(
+                f,
+                "I don't know how to classify this map, and its RSS is not 0.\
+                \n  The process is {pid} {cmdline}\
+                \n  The map is {map:?}"
+            ),
+            Error::MissingParent { pid, ppid } => write!(
+                f,
+                "Parent {ppid} of process {pid} not found. It may have exited before I could get its details"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Proc(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<ProcError> for Error {
+    fn from(e: ProcError) -> Error {
+        Error::Proc(e)
+    }
+}
+
+/// The kinds of `Error`, so that an `ErrorPolicy` can handle each of them differently.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum ErrorKind {
+    /// `Error::Proc` with `ProcError::PermissionDenied`
+    PermissionDenied,
+    /// `Error::Proc` with `ProcError::NotFound`
+    NotFound,
+    /// Any other `Error::Proc`
+    Proc,
+    MissingPss,
+    Unclassifiable,
+    MissingParent,
+}
+
+impl ErrorKind {
+    pub const ALL: [ErrorKind; 6] = [
+        ErrorKind::PermissionDenied,
+        ErrorKind::NotFound,
+        ErrorKind::Proc,
+        ErrorKind::MissingPss,
+        ErrorKind::Unclassifiable,
+        ErrorKind::MissingParent,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            ErrorKind::PermissionDenied => "permission-denied",
+            ErrorKind::NotFound => "not-found",
+            ErrorKind::Proc => "proc",
+            ErrorKind::MissingPss => "missing-pss",
+            ErrorKind::Unclassifiable => "unclassifiable",
+            ErrorKind::MissingParent => "missing-parent",
+        }
+    }
+}
+
+impl FromStr for ErrorKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<ErrorKind, String> {
+        ErrorKind::ALL
+            .into_iter()
+            .find(|k| k.name() == s)
+            .ok_or_else(|| {
+                let names: Vec<_> = ErrorKind::ALL.iter().map(|k| k.name()).collect();
+                format!(
+                    "unknown error kind \"{s}\" (expected one of {})",
+                    names.join(", ")
+                )
+            })
+    }
+}
+
+/// What to do when an error happens.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Action {
+    /// Leave out whatever the error is about (the process, the mapping, or the link to the parent)
+    /// and carry on.
+    Skip,
+    /// Like `Skip`, but log a warning first.
+    Warn,
+    /// Stop and return the error.
+    Fail,
+}
+
+impl FromStr for Action {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Action, String> {
+        match s {
+            "skip" => Ok(Action::Skip),
+            "warn" => Ok(Action::Warn),
+            "fail" => Ok(Action::Fail),
+            _ => Err(format!(
+                "unknown action \"{s}\" (expected one of skip, warn, fail)"
+            )),
+        }
+    }
+}
+
+/// The `Action` to take for each `ErrorKind`. By default, everything that can be caused by a
+/// process changing or exiting while it is being read gets a warning, and anything else fails.
+#[derive(Clone, Debug)]
+pub struct ErrorPolicy {
+    actions: HashMap<ErrorKind, Action>,
+}
+
+impl Default for ErrorPolicy {
+    fn default() -> ErrorPolicy {
+        ErrorPolicy::new()
+            .with(ErrorKind::PermissionDenied, Action::Warn)
+            .with(ErrorKind::NotFound, Action::Warn)
+            .with(ErrorKind::Proc, Action::Fail)
+            .with(ErrorKind::MissingPss, Action::Warn)
+            .with(ErrorKind::Unclassifiable, Action::Warn)
+            .with(ErrorKind::MissingParent, Action::Warn)
+    }
+}
+
+impl ErrorPolicy {
+    /// A policy that fails on every error.
+    pub fn new() -> ErrorPolicy {
+        ErrorPolicy {
+            actions: HashMap::new(),
+        }
+    }
+
+    pub fn with(mut self, kind: ErrorKind, action: Action) -> ErrorPolicy {
+        self.set(kind, action);
+        self
+    }
+
+    pub fn set(&mut self, kind: ErrorKind, action: Action) {
+        self.actions.insert(kind, action);
+    }
+
+    pub fn get(&self, kind: ErrorKind) -> Action {
+        self.actions.get(&kind).copied().unwrap_or(Action::Fail)
+    }
+
+    /// Applies the policy to `result`. Returns `None` if the error should be skipped.
+    pub fn filter<T, E: Into<Error>>(
+        &self,
+        result: std::result::Result<T, E>,
+    ) -> Option<Result<T>> {
+        let e = match result {
+            Ok(t) => return Some(Ok(t)),
+            Err(e) => e.into(),
+        };
+        match self.get(e.kind()) {
+            Action::Skip => None,
+            Action::Warn => {
+                warn!("{e}. Ignoring.");
                 None
             }
+            Action::Fail => Some(Err(e)),
+        }
+    }
+}
+
+/// A condition on a process, used to pick which processes to read.
+#[derive(Clone, Debug)]
+pub enum Selector {
+    /// The cmdline, with its arguments joined by spaces, matches the regex.
+    Cmdline(regex::Regex),
+    /// The comm from /proc/pid/stat matches the regex.
+    Comm(regex::Regex),
+    /// The path of the executable matches the regex. Processes whose exe can't be read (e.g.
+    /// because permission is denied) don't match.
+    Exe(regex::Regex),
+    Pid(i32),
+    /// The process is a child of this pid.
+    Ppid(i32),
+    Uid(u32),
+    /// The process is in this cgroup v2 path (e.g. "/system.slice/nginx.service") or one of its
+    /// descendants.
+    Cgroup(String),
+    /// Every selector matches. True if the list is empty.
+    All(Vec<Selector>),
+    /// At least one selector matches. False if the list is empty.
+    Any(Vec<Selector>),
+    /// The selector doesn't match.
+    Not(Box<Selector>),
+}
+
+impl Selector {
+    pub fn matches(&self, proc: &Proc) -> bool {
+        match self {
+            Selector::Cmdline(regex) => regex.is_match(&proc.cmdline),
+            Selector::Comm(regex) => regex.is_match(&proc.comm),
+            Selector::Exe(regex) => proc
+                .process
+                .exe()
+                .is_ok_and(|exe| regex.is_match(&exe.to_string_lossy())),
+            Selector::Pid(pid) => proc.pid == *pid,
+            Selector::Ppid(ppid) => proc.ppid == *ppid,
+            Selector::Uid(uid) => proc.uid == *uid,
+            Selector::Cgroup(path) => proc
+                .cgroup
+                .as_deref()
+                .is_some_and(|cgroup| cgroup_is_within(cgroup, path)),
+            Selector::All(selectors) => selectors.iter().all(|s| s.matches(proc)),
+            Selector::Any(selectors) => selectors.iter().any(|s| s.matches(proc)),
+            Selector::Not(selector) => !selector.matches(proc),
+        }
+    }
+}
+
+/// Parses an expression like `user=bob and (comm=nginx or comm=php-fpm)`. A condition is
+/// `KEY=VALUE`, where KEY is one of cmdline, comm, exe, pid, ppid, user, or cgroup, or a bare
+/// regex for the cmdline. Conditions are combined with `and`, `or`, `not`, and parentheses, with
+/// `not` binding tightest and `or` loosest. A value that has spaces or unbalanced parentheses in
+/// it can be quoted with '...' or "...".
+impl FromStr for Selector {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Selector, String> {
+        let mut tokens = selector_expr::tokenize(s)?.into_iter().peekable();
+        let selector = selector_expr::parse_or(&mut tokens)?;
+        match tokens.next() {
+            None => Ok(selector),
+            Some(token) => Err(format!("expected \"and\" or \"or\", got {token}")),
+        }
+    }
+}
+
+/// The tokenizer and the recursive descent parser behind `Selector::from_str`.
+mod selector_expr {
+    use super::Selector;
+    use regex::Regex;
+    use std::fmt;
+    use std::iter::Peekable;
+    use std::str::Chars;
+    use std::vec::IntoIter;
+
+    type Tokens = Peekable<IntoIter<Token>>;
+
+    #[derive(Debug, PartialEq)]
+    pub enum Token {
+        Open,
+        Close,
+        And,
+        Or,
+        Not,
+        /// `KEY=VALUE`, or a bare cmdline regex without a key
+        Condition(Option<String>, String),
+    }
+
+    impl fmt::Display for Token {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            match self {
+                Token::Open => write!(f, "'('"),
+                Token::Close => write!(f, "')'"),
+                Token::And => write!(f, "\"and\""),
+                Token::Or => write!(f, "\"or\""),
+                Token::Not => write!(f, "\"not\""),
+                Token::Condition(Some(key), value) => write!(f, "\"{key}={value}\""),
+                Token::Condition(None, value) => write!(f, "\"{value}\""),
+            }
+        }
+    }
+
+    pub fn tokenize(s: &str) -> Result<Vec<Token>, String> {
+        let mut tokens = Vec::new();
+        let mut chars = s.chars().peekable();
+        while let Some(&c) = chars.peek() {
+            match c {
+                c if c.is_whitespace() => {
+                    chars.next();
+                }
+                '(' => {
+                    chars.next();
+                    tokens.push(Token::Open);
+                }
+                ')' => {
+                    chars.next();
+                    tokens.push(Token::Close);
+                }
+                '=' => return Err("expected a key before '='".to_string()),
+                _ => {
+                    let word = read_value(&mut chars)?;
+                    let token = match word.as_str() {
+                        "and" => Token::And,
+                        "or" => Token::Or,
+                        "not" => Token::Not,
+                        _ if chars.next_if_eq(&'=').is_none() => Token::Condition(None, word),
+                        _ => Token::Condition(Some(word), read_value(&mut chars)?),
+                    };
+                    tokens.push(token);
+                }
+            }
+        }
+        Ok(tokens)
+    }
+
+    /// Reads a quoted value, or else up to whitespace, '=', or a ')' that doesn't close a '('
+    /// inside the value, so that regexes like `(a|b)` don't need quotes.
+    fn read_value(chars: &mut Peekable<Chars>) -> Result<String, String> {
+        let mut value = String::new();
+        if let Some(quote) = chars.next_if(|&c| c == '\'' || c == '"') {
+            for c in chars.by_ref() {
+                if c == quote {
+                    return Ok(value);
+                }
+                value.push(c);
+            }
+            return Err(format!("missing closing {quote} after {quote}{value}"));
         }
-        Err(NotFound(Some(pathbuf))) => {
-            warn!("\"{}\" not found. The process may have exited before I could get its details. Ignoring.",
-                pathbuf.display());
-            None
+        let mut depth = 0;
+        while let Some(&c) = chars.peek() {
+            match c {
+                c if c.is_whitespace() && depth == 0 => break,
+                ')' if depth == 0 => break,
+                // only the first '=' separates the key from the value
+                '=' if depth == 0 && value.chars().all(|c| c.is_ascii_alphanumeric()) => break,
+                '(' => depth += 1,
+                ')' => depth -= 1,
+                _ => {}
+            }
+            value.push(c);
+            chars.next();
         }
-        other => Some(other),
+        Ok(value)
+    }
+
+    pub fn parse_or(tokens: &mut Tokens) -> Result<Selector, String> {
+        let mut any = vec![parse_and(tokens)?];
+        while tokens.next_if_eq(&Token::Or).is_some() {
+            any.push(parse_and(tokens)?);
+        }
+        Ok(if any.len() == 1 {
+            any.pop().unwrap()
+        } else {
+            Selector::Any(any)
+        })
+    }
+
+    fn parse_and(tokens: &mut Tokens) -> Result<Selector, String> {
+        let mut all = vec![parse_not(tokens)?];
+        while tokens.next_if_eq(&Token::And).is_some() {
+            all.push(parse_not(tokens)?);
+        }
+        Ok(if all.len() == 1 {
+            all.pop().unwrap()
+        } else {
+            Selector::All(all)
+        })
+    }
+
+    fn parse_not(tokens: &mut Tokens) -> Result<Selector, String> {
+        match tokens.next() {
+            Some(Token::Not) => Ok(Selector::Not(Box::new(parse_not(tokens)?))),
+            Some(Token::Open) => {
+                let selector = parse_or(tokens)?;
+                match tokens.next() {
+                    Some(Token::Close) => Ok(selector),
+                    Some(token) => Err(format!("expected ')', got {token}")),
+                    None => Err("missing ')'".to_string()),
+                }
+            }
+            Some(Token::Condition(key, value)) => condition(key.as_deref(), &value),
+            Some(token) => Err(format!("expected a condition, got {token}")),
+            None => Err("expected a condition".to_string()),
+        }
+    }
+
+    fn condition(key: Option<&str>, value: &str) -> Result<Selector, String> {
+        let regex = || Regex::new(value).map_err(|e| e.to_string());
+        let pid = |key| {
+            value
+                .parse()
+                .map_err(|_| format!("{key}= takes a pid, got \"{value}\""))
+        };
+        Ok(match key {
+            None | Some("cmdline") => Selector::Cmdline(regex()?),
+            Some("comm") => Selector::Comm(regex()?),
+            Some("exe") => Selector::Exe(regex()?),
+            Some("pid") => Selector::Pid(pid("pid")?),
+            Some("ppid") => Selector::Ppid(pid("ppid")?),
+            Some("user") => Selector::Uid(crate::cli::parse_user(value)?),
+            Some("cgroup") => Selector::Cgroup(value.to_string()),
+            Some(key) => {
+                return Err(format!(
+                    "unknown key \"{key}\" (expected one of cmdline, comm, exe, pid, ppid, user, cgroup)"
+                ))
+            }
+        })
     }
 }
 
+/// Which processes `get_processes_with_root` returns.
+#[derive(Clone, Debug, Default)]
+pub struct Selection {
+    /// The processes to include. `None` includes every process.
+    pub include: Option<Selector>,
+    /// Also include all descendants of the included processes, whether they match or not.
+    pub match_children: bool,
+    /// Leave out the processes that match, even if they are descendants of included processes.
+    pub exclude: Option<Selector>,
+    /// Include the process that is doing the reading.
+    pub match_self: bool,
+}
+
 /// Returns the list of matching `ProcNode`s.
 /// If `regex` is None, returns all running process.
 /// If `regex` is provided, every running process will have its /proc/pid/cmdline
@@ -354,31 +1786,75 @@ pub fn get_processes(
     regex: &Option<regex::Regex>,
     match_children: bool,
     match_self: bool,
-    fail_on_noperm: bool,
-) -> ProcResult<Vec<Proc>> {
+    policy: &ErrorPolicy,
+) -> Result<Vec<Proc>> {
+    let selection = Selection {
+        include: regex.clone().map(Selector::Cmdline),
+        match_children,
+        exclude: None,
+        match_self,
+    };
+    get_processes_with_root(Path::new(PROC_ROOT), &selection, policy)
+}
+
+/// Like `get_processes`, but with any `Selection`, and reads the processes from `root` instead of
+/// /proc. `root` can be a procfs mounted somewhere else (e.g. a container's) or a copy of one, as
+/// long as every `<root>/<pid>` directory has the files that will be read later: `stat`,
+/// `cmdline`, `exe` (as a symlink), and `smaps` and/or `smaps_rollup`. `match_self` only makes
+/// sense for the live /proc, so it is ignored for any other `root`.
+pub fn get_processes_with_root(
+    root: &Path,
+    selection: &Selection,
+    policy: &ErrorPolicy,
+) -> Result<Vec<Proc>> {
     // https://users.rust-lang.org/t/std-id-vs-libc-pid-t-how-to-handle/78281
-    let all_processes = process::all_processes()?;
-    let procs: Vec<Proc> = all_processes
-        .filter_map(|proc_result| {
-            let result = proc_result
-                .and_then(|process| Proc::try_from_process(process, match_self))
-                .transpose()?;
-            filter_errors(result, fail_on_noperm)
-        })
-        .collect::<ProcResult<_>>()?;
-    let Some(regex) = regex else {
-        return Ok(procs);
+    let me: Option<i32> = (!selection.match_self && root == Path::new(PROC_ROOT))
+        .then(|| std::process::id().try_into().unwrap());
+    let procs = read_all_processes(root, policy)?;
+    let procs = match &selection.include {
+        None => procs,
+        Some(include) if !selection.match_children => {
+            procs.into_iter().filter(|p| include.matches(p)).collect()
+        }
+        // slow path: build the entire process tree so we can use it to determine matches
+        Some(include) => select_with_children(procs, policy, |proc| include.matches(proc))?,
     };
+    // only now, so that the children of this process, e.g. a command it launched, aren't left
+    // without a parent in the tree
+    Ok(procs
+        .into_iter()
+        .filter(|p| me != Some(p.pid))
+        .filter(|p| !selection.exclude.as_ref().is_some_and(|e| e.matches(p)))
+        .collect())
+}
 
-    if !match_children {
-        return Ok(procs
-            .into_iter()
-            .filter(|p| regex.is_match(&p.cmdline))
-            .collect());
-    }
+/// Looks up the uid of `user` in /etc/passwd.
+pub fn uid_from_user(user: &str) -> std::io::Result<Option<u32>> {
+    let passwd = std::fs::read_to_string("/etc/passwd")?;
+    Ok(passwd.lines().find_map(|line| {
+        let mut fields = line.split(':');
+        let name = fields.next()?;
+        let uid = fields.nth(1)?;
+        (name == user).then(|| uid.parse().ok()).flatten()
+    }))
+}
 
-    // slow path: build the entire process tree so we can use it to determine matches
-    let proc_tree = build_tree(procs);
+fn read_all_processes(root: &Path, policy: &ErrorPolicy) -> Result<Vec<Proc>> {
+    let all_processes = process::all_processes_with_root(root)?;
+    all_processes
+        .filter_map(|proc_result| {
+            let result = proc_result.and_then(Proc::try_from_process);
+            policy.filter(result)
+        })
+        .collect()
+}
+
+/// Keeps the processes that match `is_match`, along with all of their descendants.
+fn select_with_children<F>(procs: Vec<Proc>, policy: &ErrorPolicy, is_match: F) -> Result<Vec<Proc>>
+where
+    F: Fn(&Proc) -> bool,
+{
+    let proc_tree = build_tree(procs, policy)?;
     let mut matched: HashSet<usize> = HashSet::new();
 
     fn add_process_recursive(
@@ -394,7 +1870,7 @@ pub fn get_processes(
     }
 
     for (proc_idx, proc_node) in proc_tree.iter().enumerate() {
-        if regex.is_match(&proc_node.proc.cmdline) {
+        if is_match(&proc_node.proc) {
             add_process_recursive(&mut matched, &proc_tree, proc_idx);
         }
     }
@@ -409,7 +1885,7 @@ pub fn get_processes(
     Ok(result)
 }
 
-fn build_tree(processes: Vec<Proc>) -> Vec<ProcNode> {
+fn build_tree(processes: Vec<Proc>, policy: &ErrorPolicy) -> Result<Vec<ProcNode>> {
     let pid_idx_map: HashMap<i32, usize> = processes
         .iter()
         .enumerate()
@@ -420,92 +1896,590 @@ fn build_tree(processes: Vec<Proc>) -> Vec<ProcNode> {
     for idx in 0..proc_tree.len() {
         let proc_node = &proc_tree[idx];
         if proc_node.proc.ppid != 0 {
-            let parent_idx = pid_idx_map
-                .get(&proc_node.proc.ppid)
-                .unwrap_or_else(|| panic!("pid {} not found in proc_map", proc_node.proc.ppid));
-            proc_tree[*parent_idx].children.push(idx);
-        }
-    }
-    proc_tree
-}
-
-pub fn get_smaps(processes: Vec<Proc>, fail_on_noperm: bool) -> ProcResult<Vec<ProcListing>> {
-    processes.into_iter().filter_map(|proc| {
-        let Proc { pid, ppid, cmdline, process, faults, .. } = proc;
-        let maps_result = filter_errors(process.smaps(), fail_on_noperm)?;
-        let maps = match maps_result {
-            Ok(maps) => maps,
-            Err(e) => return Some(Err(e)),
-        }; // TODO: moar elegance
-        let exe_result = filter_errors(process.exe(), fail_on_noperm)?;
-        let exe = match exe_result {
-            Ok(exe) => exe,
-            Err(e) => return Some(Err(e)),
+            let Proc { pid, ppid, .. } = proc_node.proc;
+            let parent = pid_idx_map
+                .get(&ppid)
+                .ok_or(Error::MissingParent { pid, ppid });
+            // If the parent is skipped, this process becomes a root.
+            if let Some(parent_idx) = policy.filter(parent) {
+                proc_tree[*parent_idx?].children.push(idx);
+            }
+        }
+    }
+    Ok(proc_tree)
+}
+
+/// What `read_smaps` found out about a process, beyond its `ProcListing`.
+struct SmapsDetails {
+    process: Process,
+    num_mappings: usize,
+    skipped: Vec<SkippedMapping>,
+}
+
+fn read_smaps(
+    proc: Proc,
+    policy: &ErrorPolicy,
+    options: &SmapsOptions,
+) -> Option<Result<(ProcListing, SmapsDetails)>> {
+    let SmapsOptions { rules, keep_vmas } = options;
+    let Proc {
+        pid,
+        ppid,
+        cmdline,
+        comm,
+        cgroup,
+        process,
+        faults,
+        ..
+    } = proc;
+    let maps_result = policy.filter(process.smaps())?;
+    let maps = match maps_result {
+        Ok(maps) => maps,
+        Err(e) => return Some(Err(e)),
+    }; // TODO: moar elegance
+    let exe_result = policy.filter(process.exe())?;
+    let exe = match exe_result {
+        Ok(exe) => exe,
+        Err(e) => return Some(Err(e)),
+    };
+    let num_mappings = maps.len();
+    let mut skipped = Vec::new();
+    let mut memory_ext = MemoryExt::new();
+    let mut vmas = keep_vmas.then(|| Vec::with_capacity(num_mappings));
+    for map in maps {
+        // https://users.rust-lang.org/t/lazy-evaluation-in-pattern-matching/127565/2
+        // Returns None if the mapping should be left out
+        let get_stats_or_warn = |map_type: String, skipped: &mut Vec<SkippedMapping>| {
+            let stats = MemStats::from_map(&map);
+            if map.extension.map.contains_key("Pss") {
+                Some(Ok(stats))
+            } else if let Some(&rss) = map.extension.map.get("Rss") {
+                if rss == 0 {
+                    warn!(
+                        "PSS field not defined on {0}, but RSS is defined and is 0. Assuming 0.\
+                        \n  The process is {2} {3}\
+                        \n  The map is {1:?}",
+                        map_type, map, pid, cmdline
+                    );
+                    skipped.push(SkippedMapping::new(map.clone(), SkipReason::NoPssZeroRss));
+                    Some(Ok(stats))
+                } else {
+                    let error = Error::MissingPss {
+                        pid,
+                        cmdline: cmdline.clone(),
+                        map_type,
+                        map: Box::new(map.clone()),
+                    };
+                    let result = policy.filter(Err(error));
+                    if result.is_none() {
+                        skipped.push(SkippedMapping::new(map.clone(), SkipReason::NoPss));
+                    }
+                    result
+                }
+            } else {
+                warn!(
+                    "PSS field not defined on {0}, but neither is RSS. Assuming 0.\
+                    \n  The process is {2} {3}\
+                    \n  The map is {1:?}",
+                    map_type, map, pid, cmdline
+                );
+                skipped.push(SkippedMapping::new(map.clone(), SkipReason::NoPssNoRss));
+                Some(Ok(stats))
+            }
         };
-        let mut memory_ext = MemoryExt::new();
-        for map in maps {
-            // https://users.rust-lang.org/t/lazy-evaluation-in-pattern-matching/127565/2
-            let get_pss_or_warn = |map_type: String| {
-                if let Some(&pss) = map.extension.map.get("Pss") {
-                    pss
-                } else if let Some(&rss) = map.extension.map.get("Rss") {
-                    if rss == 0 {
-                        warn!("PSS field not defined on {0}, but RSS is defined and is 0. Assuming 0.\
-                            \n  The process is {2} {3}\
-                            \n  The map is {1:?}", map_type, map, pid, cmdline);
-                        0
+        let is_self = matches!(&map.pathname, Path(pathbuf) if *pathbuf == exe);
+        let (field, label, category) = if let Some(category) =
+            rules.categorize(&map.pathname, map.perms, is_self)
+        {
+            (
+                memory_ext
+                    .custom_map
+                    .entry(category.to_string())
+                    .or_default(),
+                format!("{category} map"),
+                MemCategory::Custom(category.to_string()),
+            )
+        } else {
+            match &map.pathname {
+                Path(pathbuf) => {
+                    let path = pathbuf.to_string_lossy();
+                    if map.extension.vm_flags.contains(VmFlags::HT) {
+                        let path = path.strip_suffix(DELETED_SUFFIX).unwrap_or(&path);
+                        (
+                            memory_ext.hugetlb_map.entry(path.to_string()).or_default(),
+                            format!("hugetlbfs map {path}"),
+                            MemCategory::Hugetlb(path.to_string()),
+                        )
+                    } else if let Some(name) = path.strip_prefix(MEMFD_PREFIX) {
+                        let name = name.strip_suffix(DELETED_SUFFIX).unwrap_or(name);
+                        (
+                            memory_ext.memfd_map.entry(name.to_string()).or_default(),
+                            format!("memfd {name}"),
+                            MemCategory::Memfd(name.to_string()),
+                        )
+                    } else if path == SHARED_ANON_PATH {
+                        (
+                            memory_ext.anon_shmem_map.entry(String::new()).or_default(),
+                            "shared anonymous map".to_string(),
+                            MemCategory::AnonShmem(String::new()),
+                        )
+                    } else if let Some(deleted) = path.strip_suffix(DELETED_SUFFIX) {
+                        let f = FileMapping::new(is_self, PathBuf::from(deleted), map.perms);
+                        let category = MemCategory::Deleted(f.clone().into());
+                        (
+                            memory_ext.deleted_map.entry(f).or_default(),
+                            "deleted file-backed map".to_string(),
+                            category,
+                        )
                     } else {
-                        panic!("FATAL: PSS field not defined on {0}, and its RSS is not 0.\
-                            \n  The process is {2} {3}\
-                            \n  The map is {1:?}", map_type, map, pid, cmdline);
+                        let f = FileMapping::new(is_self, pathbuf.clone(), map.perms);
+                        let category = MemCategory::File(f.clone().into());
+                        (
+                            memory_ext.file_map.entry(f).or_default(),
+                            "file-backed map".to_string(),
+                            category,
+                        )
                     }
-                } else {
-                    warn!("PSS field not defined on {0}, but neither is RSS. Assuming 0.\
-                        \n  The process is {2} {3}\
-                        \n  The map is {1:?}", map_type, map, pid, cmdline);
-                    0
                 }
-            };
-            let (field, label) = match &map.pathname {
-                Path(pathbuf) => (
-                    memory_ext.file_map.entry(FileMapping::new(exe == *pathbuf, pathbuf.clone(), map.perms)).or_default(),
-                   "file-backed map".to_string()
+                Heap => (&mut memory_ext.heap, "heap".to_string(), MemCategory::Heap),
+                Stack => (
+                    &mut memory_ext.stack,
+                    "stack".to_string(),
+                    MemCategory::Stack,
+                ),
+                TStack(tid) => (
+                    &mut memory_ext.thread_stack,
+                    format!("thread {tid} stack"),
+                    MemCategory::TStack,
                 ),
-                Heap => (&mut memory_ext.heap_pss, "heap".to_string()),
-                Stack => (&mut memory_ext.stack_pss, "stack".to_string()),
-                TStack(tid) => (&mut memory_ext.thread_stack_pss, format!("thread {tid} stack")),
-                Anonymous => (&mut memory_ext.anon_map_pss, "anonymous map".to_string()),
-                Vdso => (&mut memory_ext.vdso_pss, "vdso".to_string()),
-                Vvar => (&mut memory_ext.vvar_pss, "vvar".to_string()),
-                Vsyscall => (&mut memory_ext.vsyscall_pss, "vsyscall".to_string()),
-                Vsys(key) => (&mut memory_ext.vsys_pss, format!("shared memory segment (key {key})")),
-                Other(path) => (
-                    memory_ext.other_map.entry(path.clone()).or_insert(0),
-                    format!("other path {path}")
+                Anonymous => (
+                    &mut memory_ext.anon_map,
+                    "anonymous map".to_string(),
+                    MemCategory::Anonymous,
                 ),
+                Vdso => (&mut memory_ext.vdso, "vdso".to_string(), MemCategory::Vdso),
+                Vvar => (&mut memory_ext.vvar, "vvar".to_string(), MemCategory::Vvar),
+                Vsyscall => (
+                    &mut memory_ext.vsyscall,
+                    "vsyscall".to_string(),
+                    MemCategory::Vsyscall,
+                ),
+                Vsys(key) => (
+                    &mut memory_ext.vsys,
+                    format!("shared memory segment (key {key})"),
+                    MemCategory::Vsys,
+                ),
+                Other(path) => {
+                    if let Some(name) = path.strip_prefix("anon:") {
+                        (
+                            memory_ext
+                                .named_anon_map
+                                .entry(name.to_string())
+                                .or_default(),
+                            format!("anonymous map {name}"),
+                            MemCategory::NamedAnon(name.to_string()),
+                        )
+                    } else if let Some(name) = path.strip_prefix("anon_shmem:") {
+                        (
+                            memory_ext
+                                .anon_shmem_map
+                                .entry(name.to_string())
+                                .or_default(),
+                            format!("shared anonymous map {name}"),
+                            MemCategory::AnonShmem(name.to_string()),
+                        )
+                    } else {
+                        (
+                            memory_ext.other_map.entry(path.clone()).or_default(),
+                            format!("other path {path}"),
+                            MemCategory::Other(path.clone()),
+                        )
+                    }
+                }
                 _ => {
                     let Some(&rss) = map.extension.map.get("Rss") else {
-                        warn!("I don't know how to classify this map, and it doesn't have a RSS field.\
-                            \n  The process is {1} {2}\
-                            \n  The map is {0:?}", map, pid, cmdline);
+                        warn!(
+                                "I don't know how to classify this map, and it doesn't have a RSS field.\
+                                \n  The process is {1} {2}\
+                                \n  The map is {0:?}",
+                                map, pid, cmdline
+                            );
+                        skipped.push(SkippedMapping::new(map, SkipReason::Unclassified));
                         continue;
                     };
                     if rss == 0 {
-                        warn!("I don't know how to classify this map, but at least its RSS is 0.\
-                            \n  The process is {1} {2}\
-                            \n  The map is {0:?}", map, pid, cmdline);
+                        warn!(
+                            "I don't know how to classify this map, but at least its RSS is 0.\
+                                \n  The process is {1} {2}\
+                                \n  The map is {0:?}",
+                            map, pid, cmdline
+                        );
+                        skipped.push(SkippedMapping::new(map, SkipReason::Unclassified));
                         continue;
                     } else {
-                        panic!("FATAL: I don't know how to classify this map, and its RSS is not 0.\
-                            \n  The process is {1} {2}\
-                            \n  The map is {0:?}", map, pid, cmdline);
+                        let error = Error::Unclassifiable {
+                            pid,
+                            cmdline: cmdline.clone(),
+                            map: Box::new(map.clone()),
+                        };
+                        if let Some(Err(e)) = policy.filter::<(), _>(Err(error)) {
+                            return Some(Err(e));
+                        }
+                        skipped.push(SkippedMapping::new(map, SkipReason::Unclassified));
+                        continue;
                     }
-                },
-            }; // end match
-            *field += get_pss_or_warn(label);
-        } // end for map in maps
-        Some(Ok(ProcListing { pid, ppid, cmdline, faults, memory_ext }))
-    }).collect()
+                }
+            }
+        }; // end match
+        match get_stats_or_warn(label, &mut skipped) {
+            Some(Ok(stats)) => {
+                *field = *field + stats;
+                if let Some(vmas) = &mut vmas {
+                    vmas.push(Vma::new(&map, category, stats));
+                }
+            }
+            Some(Err(e)) => return Some(Err(e)),
+            None => {}
+        }
+    } // end for map in maps
+    let listing = ProcListing {
+        pid,
+        ppid,
+        cmdline,
+        comm,
+        cgroup,
+        faults,
+        memory_ext,
+        vmas,
+    };
+    Some(Ok((
+        listing,
+        SmapsDetails {
+            process,
+            num_mappings,
+            skipped,
+        },
+    )))
+}
+
+pub fn get_smaps(processes: Vec<Proc>, policy: &ErrorPolicy) -> Result<Vec<ProcListing>> {
+    get_smaps_with(processes, policy, &SmapsOptions::default())
+}
+
+/// Options for `get_smaps_with` that `get_smaps` leaves off.
+#[derive(Clone, Debug, Default)]
+pub struct SmapsOptions {
+    /// The mappings that match one of these go in `MemoryExt::custom_map` instead of their
+    /// built-in category.
+    pub rules: Rules,
+    /// Keep every mapping in `ProcListing::vmas` too, instead of only adding it to its category.
+    pub keep_vmas: bool,
+}
+
+/// Like `get_smaps`, with `options`.
+pub fn get_smaps_with(
+    processes: Vec<Proc>,
+    policy: &ErrorPolicy,
+    options: &SmapsOptions,
+) -> Result<Vec<ProcListing>> {
+    processes
+        .into_iter()
+        .filter_map(|proc| read_smaps(proc, policy, options))
+        .map(|result| result.map(|(listing, _)| listing))
+        .collect()
+}
+
+/// Like `get_smaps`, but also reads /proc/pid/smaps_rollup for every process so that the
+/// categorized total can be checked against the kernel's own sum.
+pub fn get_smaps_validated(
+    processes: Vec<Proc>,
+    policy: &ErrorPolicy,
+) -> Result<Vec<(ProcListing, Reconciliation)>> {
+    processes
+        .into_iter()
+        .filter_map(|proc| {
+            let (listing, details) = match read_smaps(proc, policy, &SmapsOptions::default())? {
+                Ok(read) => read,
+                Err(e) => return Some(Err(e)),
+            };
+            let rollup = match policy.filter(read_rollup(&details.process))? {
+                Ok(stats) => stats,
+                Err(e) => return Some(Err(e)),
+            };
+            let reconciliation = Reconciliation {
+                rollup,
+                total: listing.memory_ext.total(),
+                num_mappings: details.num_mappings,
+                skipped: details.skipped,
+            };
+            Some(Ok((listing, reconciliation)))
+        })
+        .collect()
+}
+
+/// Sums up every entry of /proc/pid/smaps_rollup. The kernel only ever writes one entry there,
+/// but `MemoryMaps` doesn't know that.
+fn read_rollup(process: &Process) -> ProcResult<MemStats> {
+    let rollup = process.smaps_rollup()?;
+    Ok(rollup
+        .memory_map_rollup
+        .0
+        .iter()
+        .map(|map| MemStats::from_smaps(&map.extension.map))
+        .sum())
+}
+
+/// A faster alternative to `get_smaps` that reads /proc/pid/smaps_rollup, where the kernel has
+/// already summed up every mapping of the process. The catch is that the memory is not broken down
+/// into categories: the whole process ends up in `MemoryExt::unclassified`.
+pub fn get_smaps_rollup(processes: Vec<Proc>, policy: &ErrorPolicy) -> Result<Vec<ProcListing>> {
+    processes
+        .into_iter()
+        .filter_map(|proc| {
+            let Proc {
+                pid,
+                ppid,
+                cmdline,
+                comm,
+                cgroup,
+                process,
+                faults,
+                ..
+            } = proc;
+            let unclassified = match policy.filter(read_rollup(&process))? {
+                Ok(stats) => stats,
+                Err(e) => return Some(Err(e)),
+            };
+            let memory_ext = MemoryExt {
+                unclassified,
+                ..MemoryExt::new()
+            };
+            Some(Ok(ProcListing {
+                pid,
+                ppid,
+                cmdline,
+                comm,
+                cgroup,
+                faults,
+                memory_ext,
+                vmas: None,
+            }))
+        })
+        .collect()
+}
+
+/// Every matched process's memory at one point in time. This is what smaps-snapshot saves and loads.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Snapshot {
+    pub time: SystemTime,
+    pub procs: Vec<ProcListing>,
+}
+
+impl Snapshot {
+    /// A snapshot of `procs`, taken now.
+    pub fn new(procs: Vec<ProcListing>) -> Snapshot {
+        Snapshot {
+            time: SystemTime::now(),
+            procs,
+        }
+    }
+
+    /// Matches up the processes of `self` and `after`. Processes are the same if they have the same
+    /// pid and cmdline, so a pid that was reused by another program counts as one process vanishing
+    /// and a new one appearing.
+    pub fn diff(&self, after: &Snapshot) -> Vec<Diff<ProcListing>> {
+        let mut diffs: HashMap<(i32, &str), Diff<ProcListing>> = HashMap::new();
+        for proc in &self.procs {
+            diffs.entry((proc.pid, &proc.cmdline)).or_default().before = Some(proc.clone());
+        }
+        for proc in &after.procs {
+            diffs.entry((proc.pid, &proc.cmdline)).or_default().after = Some(proc.clone());
+        }
+        diffs.into_values().collect()
+    }
+}
+
+/// Something that is in one or both of two snapshots, like a process or one of its memory
+/// categories. `before` is `None` if it is new, and `after` is `None` if it is gone.
+#[derive(Clone, Debug)]
+pub struct Diff<T> {
+    pub before: Option<T>,
+    pub after: Option<T>,
+}
+
+impl<T> Default for Diff<T> {
+    fn default() -> Diff<T> {
+        Diff {
+            before: None,
+            after: None,
+        }
+    }
+}
+
+impl<T> Diff<T> {
+    pub fn is_new(&self) -> bool {
+        self.before.is_none()
+    }
+
+    pub fn is_gone(&self) -> bool {
+        self.after.is_none()
+    }
+}
+
+impl Diff<MemStats> {
+    /// How much `metric` grew, or shrank if negative. A side that is missing counts as 0.
+    pub fn change(&self, metric: Metric) -> i64 {
+        let get = |stats: &Option<MemStats>| stats.map_or(0, |s| s.get(metric) as i64);
+        get(&self.after) - get(&self.before)
+    }
+}
+
+impl Diff<ProcListing> {
+    pub fn pid(&self) -> i32 {
+        self.before.as_ref().or(self.after.as_ref()).unwrap().pid
+    }
+
+    pub fn cmdline(&self) -> &str {
+        &self
+            .before
+            .as_ref()
+            .or(self.after.as_ref())
+            .unwrap()
+            .cmdline
+    }
+
+    /// The change in the process's total memory. A side that is missing counts as 0.
+    pub fn change(&self, metric: Metric) -> i64 {
+        let get = |proc: &Option<ProcListing>| {
+            proc.as_ref()
+                .map_or(0, |p| p.memory_ext.total().get(metric) as i64)
+        };
+        get(&self.after) - get(&self.before)
+    }
+
+    /// Like `MemoryExt::diff`, except that every category of a new process is new, and every
+    /// category of a process that is gone is gone.
+    pub fn memory_diff(&self, mask: &FMask) -> HashMap<MemCategory, Diff<MemStats>> {
+        diff_categories(
+            self.before.as_ref().map(|p| &p.memory_ext),
+            self.after.as_ref().map(|p| &p.memory_ext),
+            mask,
+        )
+    }
+}
+
+/// A process along with its descendants, where the memory of the whole subtree is added up.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ProcTree {
+    pub proc: ProcListing,
+    /// The memory of this process and all of its descendants
+    pub subtree: MemoryExt,
+    pub children: Vec<ProcTree>,
+}
+
+/// Arranges `processes` into trees by their ppid. A process whose parent isn't in `processes`
+/// becomes the root of a tree.
+pub fn build_proc_trees(processes: Vec<ProcListing>) -> Vec<ProcTree> {
+    let pids: HashSet<i32> = processes.iter().map(|p| p.pid).collect();
+    let mut children: HashMap<i32, Vec<ProcListing>> = HashMap::new();
+    let mut roots = Vec::new();
+    for proc in processes {
+        if proc.ppid != proc.pid && pids.contains(&proc.ppid) {
+            children.entry(proc.ppid).or_default().push(proc);
+        } else {
+            roots.push(proc);
+        }
+    }
+
+    fn grow(proc: ProcListing, children: &mut HashMap<i32, Vec<ProcListing>>) -> ProcTree {
+        let children: Vec<ProcTree> = children
+            .remove(&proc.pid)
+            .unwrap_or_default()
+            .into_iter()
+            .map(|child| grow(child, children))
+            .collect();
+        let subtree = children
+            .iter()
+            .fold(proc.memory_ext.clone(), |mem, child| mem + &child.subtree);
+        ProcTree {
+            proc,
+            subtree,
+            children,
+        }
+    }
+
+    roots
+        .into_iter()
+        .map(|root| grow(root, &mut children))
+        .collect()
+}
+
+/// The memory of every process in one cgroup.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CgroupListing {
+    /// `None` for the processes whose cgroup is unknown
+    pub cgroup: Option<String>,
+    pub pids: Vec<i32>,
+    pub faults: Faults,
+    pub memory_ext: MemoryExt,
+}
+
+/// Adds up `processes` by the cgroup they are directly in. The memory of a cgroup's descendants is
+/// not included in it.
+pub fn group_by_cgroup(processes: &[ProcListing]) -> Vec<CgroupListing> {
+    let mut groups: HashMap<Option<&str>, CgroupListing> = HashMap::new();
+    for proc in processes {
+        let group = groups
+            .entry(proc.cgroup.as_deref())
+            .or_insert_with(|| CgroupListing {
+                cgroup: proc.cgroup.clone(),
+                pids: Vec::new(),
+                faults: Faults::default(),
+                memory_ext: MemoryExt::new(),
+            });
+        group.pids.push(proc.pid);
+        group.faults = group.faults + proc.faults;
+        group.memory_ext = std::mem::take(&mut group.memory_ext) + &proc.memory_ext;
+    }
+    groups.into_values().collect()
+}
+
+/// What the kernel charges to a cgroup, from its memory.current and memory.stat, in bytes. This
+/// includes memory that smaps doesn't show, like the page cache and kernel memory, and it
+/// includes the cgroup's descendants.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CgroupMemory {
+    pub current: u64,
+    /// Every counter in memory.stat, e.g. "anon", "file", and "kernel"
+    pub stat: HashMap<String, u64>,
+}
+
+impl CgroupMemory {
+    /// Reads the files of the cgroup at `path` in the cgroup v2 hierarchy mounted at `root`,
+    /// which is normally `CGROUP_ROOT`.
+    pub fn read(root: &Path, path: &str) -> std::io::Result<CgroupMemory> {
+        let dir = root.join(path.trim_start_matches('/'));
+        let current = std::fs::read_to_string(dir.join("memory.current"))?;
+        let current = current.trim().parse().map_err(|e| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("memory.current: {e}"),
+            )
+        })?;
+        let stat = std::fs::read_to_string(dir.join("memory.stat"))?
+            .lines()
+            .filter_map(|line| {
+                let (key, value) = line.split_once(' ')?;
+                Some((key.to_string(), value.parse().ok()?))
+            })
+            .collect();
+        Ok(CgroupMemory { current, stat })
+    }
+
+    /// A counter from memory.stat, or 0 if it isn't there.
+    pub fn get(&self, key: &str) -> u64 {
+        self.stat.get(key).copied().unwrap_or(0)
+    }
 }
 
 pub fn sum_memory(processes: &[ProcListing]) -> MemoryExt {
@@ -515,3 +2489,52 @@ pub fn sum_memory(processes: &[ProcListing]) -> MemoryExt {
             mem + &proc_listing.memory_ext
         })
 }
+
+/// One file, aggregated as in `MemoryExt::aggregate_file_maps`, across every process that maps it.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FileListing {
+    pub file: MaskedFileMapping,
+    /// Whether the file has been deleted, in which case the path is without " (deleted)"
+    pub deleted: bool,
+    pub total: MemStats,
+    /// Every process that maps the file, even if it has none of it in memory
+    pub procs: Vec<FileUser>,
+}
+
+/// How much of a file one process has.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FileUser {
+    pub pid: i32,
+    pub cmdline: String,
+    pub stats: MemStats,
+}
+
+/// Adds up the file-backed mappings of `processes` by file, aggregated with `mask`. The mappings of
+/// deleted files are kept apart from those of files that still exist.
+pub fn group_by_file(processes: &[ProcListing], mask: &FMask) -> Vec<FileListing> {
+    let mut files: HashMap<(bool, MaskedFileMapping), FileListing> = HashMap::new();
+    for proc in processes {
+        let existing = proc.memory_ext.aggregate_file_maps(mask);
+        let deleted = proc.memory_ext.aggregate_deleted_maps(mask);
+        let maps = iter::repeat(false)
+            .zip(existing)
+            .chain(iter::repeat(true).zip(deleted));
+        for (deleted, (file, stats)) in maps {
+            let listing = files
+                .entry((deleted, file.clone()))
+                .or_insert_with(|| FileListing {
+                    file,
+                    deleted,
+                    total: MemStats::default(),
+                    procs: Vec::new(),
+                });
+            listing.total = listing.total + stats;
+            listing.procs.push(FileUser {
+                pid: proc.pid,
+                cmdline: proc.cmdline.clone(),
+                stats,
+            });
+        }
+    }
+    files.into_values().collect()
+}
@@ -13,171 +13,214 @@
  * along with this program. If not, see <https://www.gnu.org/licenses/>.
  */
 
-use clap::Parser;
+mod chart;
+mod html;
+mod openmetrics;
+mod sinks;
+mod tui;
+
+use chart::{Chart, Layer};
+use clap::{ArgGroup, CommandFactory, Parser};
 use env_logger::Builder;
-use gnuplot::TickOption::Mirror;
-use gnuplot::XAxis::X1;
-use gnuplot::YAxis::Y2;
-use gnuplot::{
-    AutoOption::*, AxesCommon, ColorType, Coordinate::*, DashType::*, Figure, LegendOption::*,
-    PlotOption::*, RGBString,
-};
-use log::{warn, LevelFilter};
+use log::{info, warn, LevelFilter};
+use openmetrics::Exporter;
+use serde::de::Error as _;
 use serde::ser::SerializeStruct;
-use serde::Serialize;
+use serde::{Deserialize, Deserializer, Serialize};
 use signal_hook::consts::signal::SIGINT;
 use signal_hook::flag as signal_flag;
+use sinks::{Influx, StatsD, Tag};
+use smaps_profiler::cli::{self, ErrorArgs, SelectionArgs};
 use smaps_profiler::{
-    add_maps, get_processes, get_smaps, FMask, Faults, MMPermissions, MaskedFileMapping, MemoryExt,
-    ProcListing,
+    add_maps, get_processes_with_root, get_smaps_rollup, get_smaps_validated, get_smaps_with,
+    group_by_cgroup, CgroupListing, CgroupMemory, FMask, Faults, MMPermissions, MaskedFileMapping,
+    MemStats, MemoryExt, Metric, ProcListing, Reconciliation, Rules, Selection, Selector,
+    SmapsOptions, CGROUP_ROOT,
 };
-use std::collections::{BTreeMap, HashMap};
-use std::io::{self, BufWriter, Write};
-use std::path::PathBuf;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fs;
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::os::unix::process::ExitStatusExt;
+use std::path::{Path, PathBuf};
+use std::process::{self, ExitStatus};
+use std::str::FromStr;
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
 use std::thread;
 use std::time::{Duration, Instant};
+use tui::Tui;
 
-// TODO: Summing the output from this program appears to underestimate memory usage by ~20kB
-// compared to smaps_rollup. Gotta figure out why.
-// TODO: x-axis on graph is somewhat broken. Need to record the time that a sample was taken and
+// Summing the output from this program underestimates Pss by a few kB compared to smaps_rollup,
+// because the kernel rounds each mapping's Pss down to a whole kB in smaps. Run with --validate to
+// see the per-process discrepancy.
+// TODO: x-axis on the gnuplot graph is somewhat broken. Need to record the time that a sample was taken and
 // print that on stdout and use that as the x position in the graph.
 #[derive(Parser)]
 #[command(version, about = "Report process memory usage by category.", long_about = None)]
+#[command(group(ArgGroup::new("exporter").args(["listen", "textfile"]).multiple(true)))]
 struct Args {
-    ///Regex to match process cmdline against
-    regex: Option<String>,
-
-    ///If --regex is given, include children of matched processes, even if they don't match the
-    ///regex.
-    #[arg(short = 'c', long, requires = "regex")]
-    match_children: bool,
-
-    ///Match the process for this program.
-    #[arg(short = 's', long, default_value_t = false)]
-    match_self: bool,
+    #[command(flatten)]
+    selector: SelectionArgs,
 
     ///Refresh interval in seconds
     #[arg(short, long, default_value_t = 1.0_f64)]
     interval: f64,
 
-    ///Fail if permission is denied to read a process's info. Default behavior is to skip the
-    ///process and continue running.
-    #[arg(short, long)]
-    fail_on_noperm: bool,
+    #[command(flatten)]
+    errors: ErrorArgs,
+
+    ///Which smaps counter to report, e.g. Pss, Rss, Private_Dirty, Shared_Clean, or Swap. Uss is
+    ///Private_Clean + Private_Dirty, the memory that would be freed if the process exited.
+    #[arg(short = 'M', long, default_value_t = Metric::Pss)]
+    metric: Metric,
+
+    ///Categorize mappings with the rules in the TOML file <FILE> before falling back to the
+    ///built-in categories. Every category gets its own column, JSON key, and graph series. See the
+    ///README for the format.
+    #[arg(long, value_name = "FILE", value_parser = cli::parse_rules)]
+    rules: Option<Rules>,
+
+    ///Read /proc/pid/smaps_rollup instead of /proc/pid/smaps. This is much faster, but the kernel
+    ///only gives the total of each process, so every category is 0 except UNCLASSIFIED, which has
+    ///all of the memory. Can't be used with the outputs that break memory down by category:
+    ///--graph, --html, --tui, --rules, and --file-mask.
+    #[arg(short, long, conflicts_with_all = ["graph", "html", "tui", "rules", "file_mask"])]
+    rollup: bool,
+
+    ///Instead of reporting memory usage, check each process's categorized total against its
+    ///smaps_rollup and report the discrepancy along with the mappings that were left out or counted
+    ///as 0
+    #[arg(long, conflicts_with_all = ["rollup", "json", "graph", "html"])]
+    validate: bool,
 
     ///Output newline delimited JSON instead of TSV to stdout
     #[arg(short, long)]
     json: bool,
 
-    ///Save graph as SVG to <FILE>
+    ///Instead of printing TSV, show the processes in an interactive terminal UI that is refreshed
+    ///every interval, along with a sparkline of their total. Arrow keys select a process and change
+    ///the column that is sorted by, r reverses the order, Enter shows the selected process's files,
+    ///f freezes the display, and q quits.
+    #[arg(long, conflicts_with_all = ["json", "validate", "by_cgroup", "replay", "show_warnings"])]
+    tui: bool,
+
+    ///Save graph to <FILE>, as PNG if <FILE> ends in .png and as SVG otherwise
     #[arg(short, long, value_name = "FILE")]
     graph: Option<PathBuf>,
 
+    ///Save a report to <FILE> as a single HTML file that can be opened in a browser without a
+    ///network connection. It has a chart that can be zoomed, with categories that can be hidden,
+    ///and the memory of each process at every point in time.
+    #[arg(long, value_name = "FILE")]
+    html: Option<PathBuf>,
+
+    ///Serve the latest sample at http://<ADDR>/metrics for Prometheus to scrape, e.g. with
+    ///127.0.0.1:9400. Every process has a gauge for each category, labelled with its pid, comm, and
+    ///cmdline, along with counters of its page faults.
+    #[arg(long, value_name = "ADDR", conflicts_with_all = ["validate", "replay"])]
+    listen: Option<String>,
+
+    ///Write the latest sample to <FILE> every interval for the textfile collector of
+    ///node_exporter, with the same metrics as --listen. <FILE> must end in .prom to be collected.
+    #[arg(long, value_name = "FILE", conflicts_with_all = ["validate", "replay"])]
+    textfile: Option<PathBuf>,
+
+    ///Also export the memory of each file that a process maps for --listen and --textfile, added
+    ///up with <MASK> as in smaps-snapshot --mask, e.g. "f" for each file or "frwxsp" for each file
+    ///and permissions. Every file of every process is its own series, so there can be many.
+    #[arg(long, value_name = "MASK", value_parser = FMask::from_str, requires = "exporter")]
+    file_mask: Option<FMask>,
+
+    ///Also write every sample as InfluxDB line protocol to <DEST>, which is "-" for stdout instead
+    ///of TSV (not with --tui), an http:// or https:// URL to POST to, like
+    ///http://localhost:8086/api/v2/write?org=ORG&bucket=BUCKET, or else a file to append to. The
+    ///token for InfluxDB is read from the INFLUX_TOKEN environment variable.
+    #[arg(long, value_name = "DEST", conflicts_with_all = ["validate", "replay", "json"])]
+    influx: Option<String>,
+
+    ///Also send every sample to the StatsD server at <ADDR> over UDP as gauges, with the values of
+    ///the tags added to the names, e.g. smaps_profiler.pss.1234.nginx.heap
+    #[arg(long, value_name = "ADDR", conflicts_with_all = ["validate", "replay"])]
+    statsd: Option<String>,
+
+    ///Like --statsd, but with DogStatsD tags instead, e.g. smaps_profiler.pss with the tags
+    ///pid:1234, comm:nginx, and category:heap
+    #[arg(long, value_name = "ADDR", conflicts_with_all = ["validate", "replay", "statsd"])]
+    dogstatsd: Option<String>,
+
+    ///Which tags --influx, --statsd, and --dogstatsd give each series: a comma-separated list of
+    ///pid, comm, cgroup, and category. Whatever the tags don't tell apart is added up, e.g.
+    ///"cgroup,category" sends each category of each cgroup.
+    #[arg(
+        long,
+        value_enum,
+        value_delimiter = ',',
+        default_value = "pid,comm,category"
+    )]
+    tags: Vec<Tag>,
+
+    ///Draw the graph with gnuplot, which must be installed, instead of drawing it directly. Only
+    ///SVG is supported.
+    #[cfg(feature = "gnuplot")]
+    #[arg(long, requires = "graph")]
+    gnuplot: bool,
+
+    ///Instead of reading processes, read the newline delimited JSON that an earlier run wrote with
+    ///--json from <FILE> ("-" for stdin) and write it out again as TSV, JSON, a graph, or an HTML
+    ///report. The metric and the categories are the ones that were recorded.
+    #[arg(long, value_name = "FILE", conflicts_with_all = ["regex", "match_children", "pids", "comm", "exe", "user", "ppid", "cgroup", "select", "any", "exclude", "proc_root", "match_self", "interval", "fail_on_noperm", "on_error", "rollup", "validate", "command"])]
+    replay: Option<PathBuf>,
+
     ///Graph major + minor page faults (only affects graph, not TSV or JSON)
     #[arg(short = 'm', long, requires = "graph")]
     graph_faults: bool,
 
+    ///Add up the processes in each cgroup and report every cgroup instead of every process, along
+    ///with the cgroup's memory.current and memory.stat if they can be read
+    #[arg(long, conflicts_with = "validate")]
+    by_cgroup: bool,
+
+    ///Where the cgroup v2 hierarchy is mounted, for reading memory.current and memory.stat
+    #[arg(long, value_name = "DIR", default_value = CGROUP_ROOT)]
+    cgroup_root: PathBuf,
+
     ///Print warnings to stderr
     #[arg(short = 'w', long)]
     show_warnings: bool,
+
+    ///Run COMMAND and profile it along with all of its descendants, instead of matching processes
+    ///with REGEX. Stops when COMMAND and all of its descendants have exited, and exits with the code
+    ///of COMMAND.
+    #[arg(last = true, value_name = "COMMAND", conflicts_with_all = ["regex", "match_children", "pids", "comm", "exe", "user", "ppid", "cgroup", "select", "any", "proc_root", "match_self"])]
+    command: Vec<String>,
 }
 
 // http://vrl.cs.brown.edu/color
-const _PALETTE1: [ColorType<&str>; 20] = [
-    RGBString("#35618f"),
-    RGBString("#61a3dc"),
-    RGBString("#7244b9"),
-    RGBString("#26cdca"),
-    RGBString("#1c875c"),
-    RGBString("#ade47c"),
-    RGBString("#707b5d"),
-    RGBString("#3fd34a"),
-    RGBString("#709f0f"),
-    RGBString("#c5c9b4"),
-    RGBString("#88502e"),
-    RGBString("#fd5925"),
-    RGBString("#8e1023"),
-    RGBString("#eac328"),
-    RGBString("#ed3e7e"),
-    RGBString("#fb9fa8"),
-    RGBString("#f7931e"),
-    RGBString("#a18ff8"),
-    RGBString("#bf11af"),
-    RGBString("#f27ff5"),
+const _PALETTE1: [&str; 20] = [
+    "#35618f", "#61a3dc", "#7244b9", "#26cdca", "#1c875c", "#ade47c", "#707b5d", "#3fd34a",
+    "#709f0f", "#c5c9b4", "#88502e", "#fd5925", "#8e1023", "#eac328", "#ed3e7e", "#fb9fa8",
+    "#f7931e", "#a18ff8", "#bf11af", "#f27ff5",
 ];
 
-const _PALETTE2: [ColorType<&str>; 20] = [
-    RGBString("#48bf8e"),
-    RGBString("#8a0458"),
-    RGBString("#93c920"),
-    RGBString("#7125bd"),
-    RGBString("#65f112"),
-    RGBString("#fd2c3b"),
-    RGBString("#1b511d"),
-    RGBString("#ef4cd0"),
-    RGBString("#40b8e1"),
-    RGBString("#73350e"),
-    RGBString("#f1cbd5"),
-    RGBString("#26496d"),
-    RGBString("#c6dbae"),
-    RGBString("#3447b4"),
-    RGBString("#f7d153"),
-    RGBString("#ca81e6"),
-    RGBString("#83976d"),
-    RGBString("#87a9fd"),
-    RGBString("#cd6810"),
-    RGBString("#ea7c97"),
+const _PALETTE2: [&str; 20] = [
+    "#48bf8e", "#8a0458", "#93c920", "#7125bd", "#65f112", "#fd2c3b", "#1b511d", "#ef4cd0",
+    "#40b8e1", "#73350e", "#f1cbd5", "#26496d", "#c6dbae", "#3447b4", "#f7d153", "#ca81e6",
+    "#83976d", "#87a9fd", "#cd6810", "#ea7c97",
 ];
 
-const PALETTE3: [ColorType<&str>; 20] = [
-    RGBString("#58b5e1"),
-    RGBString("#76295f"),
-    RGBString("#c0e15c"),
-    RGBString("#b825af"),
-    RGBString("#55f17b"),
-    RGBString("#e32851"),
-    RGBString("#15dec5"),
-    RGBString("#852405"),
-    RGBString("#b5ceaa"),
-    RGBString("#0e503e"),
-    RGBString("#f6b0ec"),
-    RGBString("#658114"),
-    RGBString("#5425df"),
-    RGBString("#fad139"),
-    RGBString("#1c4585"),
-    RGBString("#3aa609"),
-    RGBString("#fa79f5"),
-    RGBString("#6d4c2b"),
-    RGBString("#fcb790"),
-    RGBString("#7377ec"),
+const PALETTE3: [&str; 20] = [
+    "#58b5e1", "#76295f", "#c0e15c", "#b825af", "#55f17b", "#e32851", "#15dec5", "#852405",
+    "#b5ceaa", "#0e503e", "#f6b0ec", "#658114", "#5425df", "#fad139", "#1c4585", "#3aa609",
+    "#fa79f5", "#6d4c2b", "#fcb790", "#7377ec",
 ];
 
-const _PALETTE4: [ColorType<&str>; 20] = [
-    RGBString("#a1def0"),
-    RGBString("#8b123a"),
-    RGBString("#4dc172"),
-    RGBString("#e3488e"),
-    RGBString("#73f02e"),
-    RGBString("#ae4acd"),
-    RGBString("#add51f"),
-    RGBString("#333a9e"),
-    RGBString("#fcd107"),
-    RGBString("#2b3fff"),
-    RGBString("#cddb9b"),
-    RGBString("#de19f7"),
-    RGBString("#056e12"),
-    RGBString("#cc99d9"),
-    RGBString("#6e390d"),
-    RGBString("#3d99ce"),
-    RGBString("#f24219"),
-    RGBString("#145a6a"),
-    RGBString("#fc8f3b"),
-    RGBString("#447cfe"),
+const _PALETTE4: [&str; 20] = [
+    "#a1def0", "#8b123a", "#4dc172", "#e3488e", "#73f02e", "#ae4acd", "#add51f", "#333a9e",
+    "#fcd107", "#2b3fff", "#cddb9b", "#de19f7", "#056e12", "#cc99d9", "#6e390d", "#3d99ce",
+    "#f24219", "#145a6a", "#fc8f3b", "#447cfe",
 ];
 
-const PALETTE: [ColorType<&str>; 20] = PALETTE3;
+const PALETTE: [&str; 20] = PALETTE3;
 
 #[derive(Debug, Clone, Copy)]
 struct Interval {
@@ -206,28 +249,61 @@ impl Serialize for Interval {
     }
 }
 
-#[derive(Clone, Debug, Serialize)]
+impl<'de> Deserialize<'de> for Interval {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        #[derive(Deserialize)]
+        struct Millis {
+            start_millis: u64,
+            end_millis: u64,
+        }
+        let Millis {
+            start_millis,
+            end_millis,
+        } = Millis::deserialize(deserializer)?;
+        let duration = end_millis.checked_sub(start_millis).ok_or_else(|| {
+            D::Error::custom(format!(
+                "interval ends at {end_millis} ms, before it starts at {start_millis} ms"
+            ))
+        })?;
+        Ok(Interval {
+            start: Duration::from_millis(start_millis),
+            duration: Duration::from_millis(duration),
+        })
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
 struct SimpleProcListing {
     pid: i32,
     ppid: i32,
     cmdline: String,
+    #[serde(default)]
+    comm: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    cgroup: Option<String>,
     faults: Faults,
     memory: SimpleMemory,
 }
 
-impl From<ProcListing> for SimpleProcListing {
-    fn from(proc: ProcListing) -> Self {
+impl SimpleProcListing {
+    fn new(proc: ProcListing, metric: Metric, categories: &[&str]) -> Self {
         SimpleProcListing {
             pid: proc.pid,
             ppid: proc.ppid,
             cmdline: proc.cmdline,
+            comm: proc.comm,
+            cgroup: proc.cgroup,
             faults: proc.faults,
-            memory: proc.memory_ext.into(),
+            memory: SimpleMemory::new(&proc.memory_ext, metric, categories),
         }
     }
 }
 
-#[derive(Clone, Debug, Serialize, Default)]
+#[derive(Clone, Debug, Serialize, Deserialize, Default)]
+#[serde(default)]
 struct SimpleMemory {
     stack: u64,
     heap: u64,
@@ -241,30 +317,119 @@ struct SimpleMemory {
     vvar: u64,
     vsyscall: u64,
     sysv_shm: u64,
+    named_anon: HashMap<String, u64>,
+    anon_shmem: HashMap<String, u64>,
+    memfd: HashMap<String, u64>,
+    hugetlb: HashMap<String, u64>,
+    deleted_files: u64,
+    /// By the category of the rule that matched, with an entry for every category
+    custom: HashMap<String, u64>,
     other: HashMap<String, u64>,
+    unclassified: u64,
+    /// Swap of all of the categories put together, whatever the metric is
+    swap: u64,
+    /// SwapPss of all of the categories put together, whatever the metric is
+    swap_pss: u64,
+    /// Swap of each category, by its name as in `by_category`, whatever the metric is
+    swap_by_category: HashMap<String, u64>,
+    /// SwapPss of each category, by its name as in `by_category`, whatever the metric is
+    swap_pss_by_category: HashMap<String, u64>,
 }
 
-impl From<MemoryExt> for SimpleMemory {
-    fn from(mem: MemoryExt) -> Self {
-        let files = get_aggregated(&mem);
+impl SimpleMemory {
+    fn new(mem: &MemoryExt, metric: Metric, categories: &[&str]) -> Self {
+        let by_category = |metric| {
+            SimpleMemory::for_metric(mem, metric, categories)
+                .by_category(categories)
+                .into_iter()
+                .map(|(category, bytes)| (category.to_string(), bytes))
+                .collect()
+        };
+        SimpleMemory {
+            swap_by_category: by_category(Metric::Swap),
+            swap_pss_by_category: by_category(Metric::SwapPss),
+            ..SimpleMemory::for_metric(mem, metric, categories)
+        }
+    }
+
+    /// Everything but the swap of each category
+    fn for_metric(mem: &MemoryExt, metric: Metric, categories: &[&str]) -> Self {
+        let files = get_aggregated(mem);
+        let total = mem.total();
         SimpleMemory {
-            stack: mem.stack_pss,
-            heap: mem.heap_pss,
-            thread_stack: mem.thread_stack_pss,
-            bin_text: files.bin_text,
-            extern_text: files.lib_text,
-            bin_data: files.bin_data,
-            extern_data: files.lib_data,
-            anon_mappings: mem.anon_map_pss,
-            vdso: mem.vdso_pss,
-            vvar: mem.vvar_pss,
-            vsyscall: mem.vsyscall_pss,
-            sysv_shm: mem.vsys_pss,
-            other: mem.other_map,
+            stack: mem.stack.get(metric),
+            heap: mem.heap.get(metric),
+            thread_stack: mem.thread_stack.get(metric),
+            bin_text: files.bin_text.get(metric),
+            extern_text: files.lib_text.get(metric),
+            bin_data: files.bin_data.get(metric),
+            extern_data: files.lib_data.get(metric),
+            anon_mappings: mem.anon_map.get(metric),
+            vdso: mem.vdso.get(metric),
+            vvar: mem.vvar.get(metric),
+            vsyscall: mem.vsyscall.get(metric),
+            sysv_shm: mem.vsys.get(metric),
+            named_anon: get_each(&mem.named_anon_map, metric),
+            anon_shmem: get_each(&mem.anon_shmem_map, metric),
+            memfd: get_each(&mem.memfd_map, metric),
+            hugetlb: get_each(&mem.hugetlb_map, metric),
+            deleted_files: mem.deleted_map.values().map(|s| s.get(metric)).sum(),
+            custom: categories
+                .iter()
+                .map(|&c| {
+                    let value = mem.custom_map.get(c).map_or(0, |s| s.get(metric));
+                    (c.to_string(), value)
+                })
+                .collect(),
+            other: get_each(&mem.other_map, metric),
+            unclassified: mem.unclassified.get(metric),
+            swap: total.swap,
+            swap_pss: total.swap_pss,
+            swap_by_category: HashMap::new(),
+            swap_pss_by_category: HashMap::new(),
         }
     }
 }
 
+impl SimpleMemory {
+    /// Every category by its name in the JSON, with the categories of `categories` in between
+    /// deleted_files and other as in the TSV. The categories with a breakdown are added up.
+    fn by_category<'a>(&self, categories: &[&'a str]) -> Vec<(&'a str, u64)> {
+        let sum = |map: &HashMap<String, u64>| map.values().sum();
+        let mut by_category = vec![
+            ("stack", self.stack),
+            ("heap", self.heap),
+            ("thread_stack", self.thread_stack),
+            ("bin_text", self.bin_text),
+            ("extern_text", self.extern_text),
+            ("bin_data", self.bin_data),
+            ("extern_data", self.extern_data),
+            ("anon_mappings", self.anon_mappings),
+            ("vdso", self.vdso),
+            ("vvar", self.vvar),
+            ("vsyscall", self.vsyscall),
+            ("sysv_shm", self.sysv_shm),
+            ("named_anon", sum(&self.named_anon)),
+            ("anon_shmem", sum(&self.anon_shmem)),
+            ("memfd", sum(&self.memfd)),
+            ("hugetlb", sum(&self.hugetlb)),
+            ("deleted_files", self.deleted_files),
+        ];
+        for &category in categories {
+            by_category.push((category, self.custom.get(category).copied().unwrap_or(0)));
+        }
+        by_category.push(("other", sum(&self.other)));
+        by_category.push(("unclassified", self.unclassified));
+        by_category
+    }
+}
+
+fn get_each(map: &HashMap<String, MemStats>, metric: Metric) -> HashMap<String, u64> {
+    map.iter()
+        .map(|(name, stats)| (name.clone(), stats.get(metric)))
+        .collect()
+}
+
 impl std::ops::Add<&SimpleMemory> for SimpleMemory {
     type Output = SimpleMemory;
 
@@ -280,9 +445,20 @@ impl std::ops::Add<&SimpleMemory> for SimpleMemory {
             anon_mappings: self.anon_mappings + rhs.anon_mappings,
             vdso: self.vdso + rhs.vdso,
             vvar: self.vvar + rhs.vvar,
-            vsyscall: self.vsyscall + rhs.vvar,
+            vsyscall: self.vsyscall + rhs.vsyscall,
             sysv_shm: self.sysv_shm + rhs.sysv_shm,
+            named_anon: add_maps(self.named_anon, &rhs.named_anon),
+            anon_shmem: add_maps(self.anon_shmem, &rhs.anon_shmem),
+            memfd: add_maps(self.memfd, &rhs.memfd),
+            hugetlb: add_maps(self.hugetlb, &rhs.hugetlb),
+            deleted_files: self.deleted_files + rhs.deleted_files,
+            custom: add_maps(self.custom, &rhs.custom),
             other: add_maps(self.other, &rhs.other),
+            unclassified: self.unclassified + rhs.unclassified,
+            swap: self.swap + rhs.swap,
+            swap_pss: self.swap_pss + rhs.swap_pss,
+            swap_by_category: add_maps(self.swap_by_category, &rhs.swap_by_category),
+            swap_pss_by_category: add_maps(self.swap_pss_by_category, &rhs.swap_pss_by_category),
         }
     }
 }
@@ -295,61 +471,122 @@ impl std::iter::Sum for SimpleMemory {
 
 #[derive(Debug, Clone, Copy, Default)]
 struct FileCategoryTotals {
-    bin_text: u64,
-    lib_text: u64,
-    bin_data: u64,
-    lib_data: u64,
+    bin_text: MemStats,
+    lib_text: MemStats,
+    bin_data: MemStats,
+    lib_data: MemStats,
 }
 
 fn get_aggregated(mem: &MemoryExt) -> FileCategoryTotals {
     let aggregated = mem.aggregate_file_maps(&FMask::new(true, false, MMPermissions::EXECUTE));
     FileCategoryTotals {
-        bin_text: *aggregated
+        bin_text: aggregated
             .get(&MaskedFileMapping::new(
                 Some(true),
                 None,
                 MMPermissions::EXECUTE,
             ))
-            .unwrap_or(&0),
-        lib_text: *aggregated
+            .copied()
+            .unwrap_or_default(),
+        lib_text: aggregated
             .get(&MaskedFileMapping::new(
                 Some(false),
                 None,
                 MMPermissions::EXECUTE,
             ))
-            .unwrap_or(&0),
-        bin_data: *aggregated
+            .copied()
+            .unwrap_or_default(),
+        bin_data: aggregated
             .get(&MaskedFileMapping::new(
                 Some(true),
                 None,
                 MMPermissions::NONE,
             ))
-            .unwrap_or(&0),
-        lib_data: *aggregated
+            .copied()
+            .unwrap_or_default(),
+        lib_data: aggregated
             .get(&MaskedFileMapping::new(
                 Some(false),
                 None,
                 MMPermissions::NONE,
             ))
-            .unwrap_or(&0),
+            .copied()
+            .unwrap_or_default(),
     }
 }
 
-#[derive(Debug, Clone, Serialize)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct SimpleCgroupListing {
+    cgroup: Option<String>,
+    pids: Vec<i32>,
+    faults: Faults,
+    memory: SimpleMemory,
+    cgroup_memory: Option<CgroupMemory>,
+}
+
+impl SimpleCgroupListing {
+    fn new(group: CgroupListing, metric: Metric, categories: &[&str], cgroup_root: &Path) -> Self {
+        let cgroup_memory = group.cgroup.as_ref().and_then(|path| {
+            CgroupMemory::read(cgroup_root, path)
+                .inspect_err(|e| info!("couldn't read the memory usage of cgroup {path}: {e}"))
+                .ok()
+        });
+        SimpleCgroupListing {
+            cgroup: group.cgroup,
+            pids: group.pids,
+            faults: group.faults,
+            memory: SimpleMemory::new(&group.memory_ext, metric, categories),
+            cgroup_memory,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 struct Message {
     interval: Interval,
+    /// `None` in recordings from before the metric was recorded
+    #[serde(default)]
+    metric: Option<Metric>,
     all: SimpleMemory,
     procs: Vec<SimpleProcListing>,
+    /// Only filled in with --by-cgroup, in which case `procs` is empty
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    cgroups: Vec<SimpleCgroupListing>,
     acc_faults: Faults,
 }
 
 impl Message {
-    fn new(procs: Vec<ProcListing>, interval: Interval, acc_faults: Faults) -> Message {
-        let procs: Vec<SimpleProcListing> = procs.into_iter().map(|p| p.into()).collect();
+    /// If `cgroup_root` is given, the processes are grouped by cgroup.
+    fn new(
+        procs: Vec<ProcListing>,
+        interval: Interval,
+        acc_faults: Faults,
+        metric: Metric,
+        categories: &[&str],
+        cgroup_root: Option<&Path>,
+    ) -> Message {
+        let cgroups: Vec<SimpleCgroupListing> = match cgroup_root {
+            Some(root) => group_by_cgroup(&procs)
+                .into_iter()
+                .map(|g| SimpleCgroupListing::new(g, metric, categories, root))
+                .collect(),
+            None => Vec::new(),
+        };
+        let procs: Vec<SimpleProcListing> = procs
+            .into_iter()
+            .map(|p| SimpleProcListing::new(p, metric, categories))
+            .collect();
+        let all = procs.iter().map(|p| p.memory.clone()).sum();
         Message {
             interval,
-            all: procs.iter().map(|p| p.memory.clone()).sum(),
-            procs,
+            metric: Some(metric),
+            all,
+            procs: if cgroup_root.is_some() {
+                Vec::new()
+            } else {
+                procs
+            },
+            cgroups,
             acc_faults,
         }
     }
@@ -374,48 +611,183 @@ fn main() -> io::Result<()> {
     // the same as the length of the input list. At least, I know of no way to do this in Rust.
     let program_start = Instant::now();
     let args = Args::parse();
+    if let Some(rules) = &args.rules {
+        if let Err(e) = check_categories(&rules.categories()) {
+            Args::command()
+                .error(clap::error::ErrorKind::ValueValidation, e)
+                .exit();
+        }
+    }
+    if args.tui && args.influx.as_deref() == Some("-") {
+        Args::command()
+            .error(
+                clap::error::ErrorKind::ArgumentConflict,
+                "--influx - writes to stdout, which --tui draws on",
+            )
+            .exit();
+    }
+    // anything on stderr would end up in the middle of the TUI
     if args.show_warnings {
         Builder::from_default_env()
             .filter_level(LevelFilter::Warn)
             .init();
-    } else {
+    } else if !args.tui {
         env_logger::init();
     }
+    if let Some(path) = &args.replay {
+        return replay(&args, path);
+    }
     let target_duration = Duration::try_from_secs_f64(args.interval).unwrap();
-    let re = args.regex.map(|s| regex::Regex::new(&s).unwrap());
-    let mut all_messages: Option<Vec<Message>> = args.graph.as_ref().map(|_| Vec::new());
+    let selection = args.selector.selection();
+    let policy = args.errors.policy();
+    let options = SmapsOptions {
+        rules: args.rules.clone().unwrap_or_default(),
+        ..SmapsOptions::default()
+    };
+    let categories = options.rules.categories();
+    let mut all_messages: Option<Vec<Message>> =
+        (args.graph.is_some() || args.html.is_some()).then(Vec::new);
     let term = Arc::new(AtomicBool::new(false));
     signal_flag::register(SIGINT, Arc::clone(&term))?;
     let mut pid_faults_map = HashMap::new();
+    // before the TUI takes over the terminal, which would hide these errors
+    let exporter = if args.listen.is_some() || args.textfile.is_some() {
+        let exporter = Exporter::new(
+            args.listen.as_deref(),
+            args.textfile.clone(),
+            args.file_mask,
+        )
+        .unwrap_or_else(|e| {
+            eprintln!("{e}");
+            process::exit(1)
+        });
+        Some(exporter)
+    } else {
+        None
+    };
+    let mut influx = args.influx.as_deref().map(|dest| {
+        Influx::new(dest, args.tags.clone()).unwrap_or_else(|e| {
+            eprintln!("Failed to open {dest}: {e}");
+            process::exit(1)
+        })
+    });
+    let statsd = match (&args.statsd, &args.dogstatsd) {
+        (Some(addr), _) => Some((addr, false)),
+        (_, Some(addr)) => Some((addr, true)),
+        _ => None,
+    };
+    let statsd = statsd.map(|(addr, dogstatsd)| {
+        StatsD::new(addr, dogstatsd, args.tags.clone()).unwrap_or_else(|e| {
+            eprintln!("Failed to connect to {addr}: {e}");
+            process::exit(1)
+        })
+    });
+    let mut child = args.command.split_first().map(|(program, program_args)| {
+        process::Command::new(program)
+            .args(program_args)
+            .spawn()
+            .unwrap_or_else(|e| {
+                eprintln!("Failed to run {program}: {e}");
+                process::exit(1)
+            })
+    });
+    let mut tui = if args.tui {
+        Some(Tui::new(args.metric, &categories)?)
+    } else {
+        None
+    };
+    // line protocol on stdout takes the place of TSV
+    let print = tui.is_none() && args.influx.as_deref() != Some("-");
+    let mut exit_status = None;
+    // the child's descendants in the last sample, which are followed after the child has exited
+    // and they have been reparented, until they have all exited too
+    let mut descendants: Vec<i32> = Vec::new();
     while !term.load(Ordering::Relaxed) {
+        if let (Some(child), None) = (&mut child, exit_status) {
+            exit_status = child.try_wait()?;
+        }
         let start = program_start.elapsed();
-        let procs = get_processes(
-            &re,
-            args.match_children,
-            args.match_self,
-            args.fail_on_noperm,
-        )
-        .unwrap();
-        let procs = get_smaps(procs, args.fail_on_noperm).unwrap();
-        let interval = Interval {
-            start,
-            duration: program_start.elapsed() - start,
+        let procs = match &child {
+            Some(child) => {
+                let pid = child.id() as i32;
+                let roots = match exit_status {
+                    None => vec![Selector::Pid(pid)],
+                    Some(_) => descendants.iter().copied().map(Selector::Pid).collect(),
+                };
+                let selection = Selection {
+                    include: Some(Selector::Any(roots)),
+                    match_children: true,
+                    ..selection.clone()
+                };
+                let procs = get_processes_with_root(&args.selector.proc_root, &selection, &policy)
+                    .unwrap_or_else(exit_on_error);
+                // the pid of the child can be reused once it has been waited for
+                descendants = procs.iter().map(|p| p.pid).filter(|&p| p != pid).collect();
+                procs
+            }
+            None => get_processes_with_root(&args.selector.proc_root, &selection, &policy)
+                .unwrap_or_else(exit_on_error),
         };
-        update_faults_map(&mut pid_faults_map, &procs);
-        let acc_faults = pid_faults_map.values().copied().sum();
-        let message = Message::new(procs, interval.clone(), acc_faults);
-        // do this first
-        if args.json {
-            print_json(&message)?;
+        // zombies are left out here, so they don't keep this going
+        let all_exited = |sampled: usize| exit_status.is_some() && sampled == 0;
+        if args.validate {
+            let procs = get_smaps_validated(procs, &policy).unwrap_or_else(exit_on_error);
+            if all_exited(procs.len()) {
+                break;
+            }
+            print_validation_tsv(&procs, args.metric)?;
         } else {
-            print_tsv(&message)?;
-        }
-        // do this second due to moving
-        if let Some(all_messages) = &mut all_messages {
-            all_messages.push(message);
+            let procs = if args.rollup {
+                get_smaps_rollup(procs, &policy)
+            } else {
+                get_smaps_with(procs, &policy, &options)
+            }
+            .unwrap_or_else(exit_on_error);
+            if all_exited(procs.len()) {
+                break;
+            }
+            let interval = Interval {
+                start,
+                duration: program_start.elapsed() - start,
+            };
+            update_faults_map(&mut pid_faults_map, &procs);
+            if let Some(tui) = &mut tui {
+                tui.update(&procs, &categories);
+            }
+            if let Some(exporter) = &exporter {
+                exporter.update(&procs, args.metric, &categories)?;
+            }
+            let acc_faults = pid_faults_map.values().copied().sum();
+            let cgroup_root = args.by_cgroup.then_some(args.cgroup_root.as_path());
+            let message = Message::new(
+                procs,
+                interval,
+                acc_faults,
+                args.metric,
+                &categories,
+                cgroup_root,
+            );
+            // do this first
+            if print {
+                print_message(&message, &args, args.metric, &categories)?;
+            }
+            if let Some(influx) = &mut influx {
+                influx.send(&message, args.metric, &categories)?;
+            }
+            if let Some(statsd) = &statsd {
+                statsd.send(&message, args.metric, &categories);
+            }
+            // do this second due to moving
+            if let Some(all_messages) = &mut all_messages {
+                all_messages.push(message);
+            }
         }
-        let now_elapsed = program_start.elapsed() - interval.start;
-        if now_elapsed < target_duration {
+        let now_elapsed = program_start.elapsed() - start;
+        if let Some(tui) = &mut tui {
+            if !tui.wait(target_duration.saturating_sub(now_elapsed))? {
+                break;
+            }
+        } else if now_elapsed < target_duration {
             thread::sleep(target_duration - now_elapsed);
         } else if now_elapsed > target_duration {
             warn!(
@@ -425,14 +797,199 @@ fn main() -> io::Result<()> {
             );
         }
     } // end loop
+    drop(tui);
+
+    // generate graph and report
+    if let Some(all_messages) = all_messages {
+        save_messages(all_messages, &args, args.metric, &categories)?;
+    }
+    if let Some(mut child) = child {
+        // after SIGINT, the child most likely got one too
+        let status = match exit_status {
+            Some(status) => status,
+            None => child.wait()?,
+        };
+        process::exit(exit_code(status));
+    }
+    Ok(())
+}
+
+/// Writes out the messages recorded in `path` as if they had just been read from the processes.
+fn replay(args: &Args, path: &Path) -> io::Result<()> {
+    let reader: Box<dyn BufRead> = if path == Path::new("-") {
+        Box::new(io::stdin().lock())
+    } else {
+        Box::new(BufReader::new(fs::File::open(path)?))
+    };
+    let mut all_messages: Option<Vec<Message>> =
+        (args.graph.is_some() || args.html.is_some()).then(Vec::new);
+    // every message has an entry for every category, so the first one has all of them
+    let mut categories: Option<Vec<String>> = args
+        .rules
+        .as_ref()
+        .map(|rules| rules.categories().into_iter().map(String::from).collect());
+    let mut metric = args.metric;
+    for (i, line) in reader.lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let message: Message = serde_json::from_str(&line).map_err(|e| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{}:{}: {e}", path.display(), i + 1),
+            )
+        })?;
+        let categories = categories.get_or_insert_with(|| {
+            let names: BTreeSet<&String> = message.all.custom.keys().collect();
+            names.into_iter().cloned().collect()
+        });
+        let categories: Vec<&str> = categories.iter().map(String::as_str).collect();
+        metric = message.metric.unwrap_or(args.metric);
+        print_message(&message, args, metric, &categories)?;
+        if let Some(all_messages) = &mut all_messages {
+            all_messages.push(message);
+        }
+    }
+    if let Some(all_messages) = all_messages {
+        let categories: Vec<&str> = categories.iter().flatten().map(String::as_str).collect();
+        save_messages(all_messages, args, metric, &categories)?;
+    }
+    Ok(())
+}
+
+/// Saves the HTML report and the graph, if they were asked for.
+fn save_messages(
+    messages: Vec<Message>,
+    args: &Args,
+    metric: Metric,
+    categories: &[&str],
+) -> io::Result<()> {
+    if let Some(path) = &args.html {
+        html::save(path, &messages, metric, categories)?;
+    }
+    if let Some(path) = &args.graph {
+        graph_memory(messages, args, metric, categories, path)?;
+    }
+    Ok(())
+}
+
+/// Prints `message` to stdout as JSON or TSV, depending on `args`.
+fn print_message(
+    message: &Message,
+    args: &Args,
+    metric: Metric,
+    categories: &[&str],
+) -> io::Result<()> {
+    if args.json {
+        print_json(message)
+    } else if args.by_cgroup || !message.cgroups.is_empty() {
+        print_cgroup_tsv(message, metric, categories)
+    } else {
+        print_tsv(message, metric, categories)
+    }
+}
+
+/// The exit code a shell would report for `status`.
+fn exit_code(status: ExitStatus) -> i32 {
+    match (status.code(), status.signal()) {
+        (Some(code), _) => code,
+        (None, Some(signal)) => 128 + signal,
+        (None, None) => 1,
+    }
+}
+
+/// Turns the category of a rule into a TSV column name, e.g. "ICU data" into "ICU_DATA".
+fn column_name(category: &str) -> String {
+    category
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() {
+                c.to_ascii_uppercase()
+            } else {
+                '_'
+            }
+        })
+        .collect()
+}
 
-    // generate graph
-    if let Some(path) = args.graph {
-        graph_memory(all_messages.unwrap(), args.graph_faults, path);
+/// Checks that no two categories, built-in or from the rules, would get the same TSV column, JSON
+/// key, or metric label.
+fn check_categories(categories: &[&str]) -> Result<(), String> {
+    let mut columns: HashMap<String, &str> = HashMap::new();
+    for (category, _) in SimpleMemory::default().by_category(&[]) {
+        columns.insert(column_name(category), category);
+        columns.insert(tsv_name(category), category);
+    }
+    for &category in categories {
+        if let Some(other) = columns.insert(column_name(category), category) {
+            return Err(format!(
+                "the rule category \"{category}\" can't be told apart from the category \"{other}\""
+            ));
+        }
     }
     Ok(())
 }
 
+/// The name of the TSV columns of a category, e.g. "ANON_MAP" for anon_mappings.
+fn tsv_name(category: &str) -> String {
+    match category {
+        "anon_mappings" => "ANON_MAP".to_string(),
+        "sysv_shm" => "SHM".to_string(),
+        "deleted_files" => "DELETED".to_string(),
+        category => column_name(category),
+    }
+}
+
+/// The header of the TSV columns of the swap of every category, e.g. HEAP_SWAP, and then of its
+/// SwapPss, e.g. HEAP_SWAP_PSS, each followed by a tab.
+fn swap_header(categories: &[&str]) -> String {
+    let names = SimpleMemory::default().by_category(categories);
+    ["SWAP", "SWAP_PSS"]
+        .iter()
+        .flat_map(|suffix| {
+            names
+                .iter()
+                .map(move |(category, _)| format!("{}_{suffix}\t", tsv_name(category)))
+        })
+        .collect()
+}
+
+/// The values of the columns of `swap_header`, each followed by a tab.
+fn swap_values(memory: &SimpleMemory, categories: &[&str]) -> String {
+    let names = memory.by_category(categories);
+    [&memory.swap_by_category, &memory.swap_pss_by_category]
+        .iter()
+        .flat_map(|map| {
+            names
+                .iter()
+                .map(|(category, _)| format!("{}\t", map.get(*category).copied().unwrap_or(0)))
+        })
+        .collect()
+}
+
+/// The header of the TSV columns for `categories`, each followed by a tab.
+fn custom_header(categories: &[&str], m: &str) -> String {
+    categories
+        .iter()
+        .map(|c| format!("{}_{m}\t", column_name(c)))
+        .collect()
+}
+
+/// The values of the TSV columns for `categories`, each followed by a tab.
+fn custom_values(custom: &HashMap<String, u64>, categories: &[&str]) -> String {
+    categories
+        .iter()
+        .map(|&c| format!("{}\t", custom.get(c).copied().unwrap_or(0)))
+        .collect()
+}
+
+fn exit_on_error<T>(e: smaps_profiler::Error) -> T {
+    tui::restore();
+    eprintln!("{e}");
+    process::exit(1)
+}
+
 fn update_faults_map(map: &mut HashMap<i32, Faults>, procs: &Vec<ProcListing>) {
     // Each process already keeps a running total, so just replace counts for existing
     // processes in the map. If new pids appear, they will be added to the map.
@@ -443,10 +1000,13 @@ fn update_faults_map(map: &mut HashMap<i32, Faults>, procs: &Vec<ProcListing>) {
     }
 }
 
-fn print_tsv(message: &Message) -> io::Result<()> {
+fn print_tsv(message: &Message, metric: Metric, categories: &[&str]) -> io::Result<()> {
     // https://rust-cli.github.io/book/tutorial/output.html#a-note-on-printing-performance
     let mut writer = BufWriter::new(io::stdout().lock());
-    writeln!(&mut writer, "PID\tSTACK_PSS\tHEAP_PSS\tTHREAD_STACK_PSS\tBIN_TEXT_PSS\tEXTERN_TEXT_PSS\tBIN_DATA_PSS\tEXTERN_DATA_PSS\tANON_MAP_PSS\tVDSO_PSS\tVVAR_PSS\tVSYSCALL_PSS\tSHM_PSS\tOTHER_PSS\tMIN_FAULTS\tMAJ_FAULTS\tCMD")?;
+    let m = metric.smaps_name().to_uppercase();
+    let custom_header = custom_header(categories, &m);
+    let swap_header = swap_header(categories);
+    writeln!(&mut writer, "PID\tSTACK_{m}\tHEAP_{m}\tTHREAD_STACK_{m}\tBIN_TEXT_{m}\tEXTERN_TEXT_{m}\tBIN_DATA_{m}\tEXTERN_DATA_{m}\tANON_MAP_{m}\tVDSO_{m}\tVVAR_{m}\tVSYSCALL_{m}\tSHM_{m}\tNAMED_ANON_{m}\tANON_SHMEM_{m}\tMEMFD_{m}\tHUGETLB_{m}\tDELETED_{m}\t{custom_header}OTHER_{m}\tUNCLASSIFIED_{m}\tSWAP\tSWAP_PSS\t{swap_header}MIN_FAULTS\tMAJ_FAULTS\tCMD")?;
     for proc_listing in &message.procs {
         let SimpleProcListing {
             pid,
@@ -468,14 +1028,150 @@ fn print_tsv(message: &Message) -> io::Result<()> {
             vvar,
             vsyscall,
             sysv_shm: vsys,
+            named_anon,
+            anon_shmem,
+            memfd,
+            hugetlb,
+            deleted_files,
+            custom,
             other,
+            unclassified,
+            swap,
+            swap_pss,
+            swap_by_category: _,
+            swap_pss_by_category: _,
         } = memory;
+        let swap_values = swap_values(memory, categories);
         let Faults {
             minor: min_faults,
             major: maj_faults,
         } = faults;
+        let named_anon: u64 = named_anon.values().sum();
+        let anon_shmem: u64 = anon_shmem.values().sum();
+        let memfd: u64 = memfd.values().sum();
+        let hugetlb: u64 = hugetlb.values().sum();
+        let custom = custom_values(custom, categories);
         let other: u64 = other.values().sum();
-        writeln!(&mut writer, "{pid}\t{stack}\t{heap}\t{thread_stack}\t{bin_text}\t{extern_text}\t{bin_data}\t{extern_data}\t{anon_mappings}\t{vdso}\t{vvar}\t{vsyscall}\t{vsys}\t{other}\t{min_faults}\t{maj_faults}\t{cmdline}")?;
+        writeln!(&mut writer, "{pid}\t{stack}\t{heap}\t{thread_stack}\t{bin_text}\t{extern_text}\t{bin_data}\t{extern_data}\t{anon_mappings}\t{vdso}\t{vvar}\t{vsyscall}\t{vsys}\t{named_anon}\t{anon_shmem}\t{memfd}\t{hugetlb}\t{deleted_files}\t{custom}{other}\t{unclassified}\t{swap}\t{swap_pss}\t{swap_values}{min_faults}\t{maj_faults}\t{cmdline}")?;
+    }
+    writer.flush()
+}
+
+fn print_cgroup_tsv(message: &Message, metric: Metric, categories: &[&str]) -> io::Result<()> {
+    let mut writer = BufWriter::new(io::stdout().lock());
+    let m = metric.smaps_name().to_uppercase();
+    let custom_header = custom_header(categories, &m);
+    let swap_header = swap_header(categories);
+    writeln!(&mut writer, "NUM_PROCS\tSTACK_{m}\tHEAP_{m}\tTHREAD_STACK_{m}\tBIN_TEXT_{m}\tEXTERN_TEXT_{m}\tBIN_DATA_{m}\tEXTERN_DATA_{m}\tANON_MAP_{m}\tVDSO_{m}\tVVAR_{m}\tVSYSCALL_{m}\tSHM_{m}\tNAMED_ANON_{m}\tANON_SHMEM_{m}\tMEMFD_{m}\tHUGETLB_{m}\tDELETED_{m}\t{custom_header}OTHER_{m}\tUNCLASSIFIED_{m}\tTOTAL_{m}\tSWAP\tSWAP_PSS\t{swap_header}MEMORY_CURRENT\tMEMORY_ANON\tMEMORY_FILE\tMIN_FAULTS\tMAJ_FAULTS\tCGROUP")?;
+    for group in &message.cgroups {
+        let SimpleCgroupListing {
+            cgroup,
+            pids,
+            faults,
+            memory,
+            cgroup_memory,
+        } = group;
+        let SimpleMemory {
+            stack,
+            heap,
+            thread_stack,
+            bin_text,
+            extern_text,
+            bin_data,
+            extern_data,
+            anon_mappings,
+            vdso,
+            vvar,
+            vsyscall,
+            sysv_shm: vsys,
+            named_anon,
+            anon_shmem,
+            memfd,
+            hugetlb,
+            deleted_files,
+            custom,
+            other,
+            unclassified,
+            swap,
+            swap_pss,
+            swap_by_category: _,
+            swap_pss_by_category: _,
+        } = memory;
+        let swap_values = swap_values(memory, categories);
+        let Faults {
+            minor: min_faults,
+            major: maj_faults,
+        } = faults;
+        let num_procs = pids.len();
+        let named_anon: u64 = named_anon.values().sum();
+        let anon_shmem: u64 = anon_shmem.values().sum();
+        let memfd: u64 = memfd.values().sum();
+        let hugetlb: u64 = hugetlb.values().sum();
+        let custom_total: u64 = custom.values().sum();
+        let custom = custom_values(custom, categories);
+        let other: u64 = other.values().sum();
+        let total = stack
+            + heap
+            + thread_stack
+            + bin_text
+            + extern_text
+            + bin_data
+            + extern_data
+            + anon_mappings
+            + vdso
+            + vvar
+            + vsyscall
+            + vsys
+            + named_anon
+            + anon_shmem
+            + memfd
+            + hugetlb
+            + deleted_files
+            + custom_total
+            + other
+            + unclassified;
+        let (current, anon, file) = match cgroup_memory {
+            Some(mem) => (
+                mem.current.to_string(),
+                mem.get("anon").to_string(),
+                mem.get("file").to_string(),
+            ),
+            None => ("-".to_string(), "-".to_string(), "-".to_string()),
+        };
+        let cgroup = cgroup.as_deref().unwrap_or("-");
+        writeln!(&mut writer, "{num_procs}\t{stack}\t{heap}\t{thread_stack}\t{bin_text}\t{extern_text}\t{bin_data}\t{extern_data}\t{anon_mappings}\t{vdso}\t{vvar}\t{vsyscall}\t{vsys}\t{named_anon}\t{anon_shmem}\t{memfd}\t{hugetlb}\t{deleted_files}\t{custom}{other}\t{unclassified}\t{total}\t{swap}\t{swap_pss}\t{swap_values}{current}\t{anon}\t{file}\t{min_faults}\t{maj_faults}\t{cgroup}")?;
+    }
+    writer.flush()
+}
+
+fn print_validation_tsv(procs: &[(ProcListing, Reconciliation)], metric: Metric) -> io::Result<()> {
+    let mut writer = BufWriter::new(io::stdout().lock());
+    let m = metric.smaps_name().to_uppercase();
+    writeln!(
+        &mut writer,
+        "PID\tROLLUP_{m}\tTOTAL_{m}\tDIFF_{m}\tROUNDING_BOUND\tEXPLAINED\tSKIPPED\tCMD"
+    )?;
+    for (proc_listing, reconciliation) in procs {
+        let ProcListing { pid, cmdline, .. } = proc_listing;
+        let rollup = reconciliation.rollup.get(metric);
+        let total = reconciliation.total.get(metric);
+        let diff = reconciliation.discrepancy(metric);
+        let bound = reconciliation.rounding_bound(metric);
+        let explained = reconciliation.is_explained(metric);
+        let skipped = if reconciliation.skipped.is_empty() {
+            "-".to_string()
+        } else {
+            reconciliation
+                .skipped
+                .iter()
+                .map(|s| s.to_string())
+                .collect::<Vec<_>>()
+                .join("; ")
+        };
+        writeln!(
+            &mut writer,
+            "{pid}\t{rollup}\t{total}\t{diff}\t{bound}\t{explained}\t{skipped}\t{cmdline}"
+        )?;
     }
     writer.flush()
 }
@@ -487,9 +1183,16 @@ fn print_json(messages: &Message) -> io::Result<()> {
     writer.flush()
 }
 
-fn graph_memory(messages: Vec<Message>, graph_faults: bool, out: PathBuf) {
+fn graph_memory(
+    messages: Vec<Message>,
+    args: &Args,
+    metric: Metric,
+    categories: &[&str],
+    out: &Path,
+) -> io::Result<()> {
     if messages.is_empty() {
         eprintln!("Nothing to plot.");
+        return Ok(());
     }
     let empty_vec: Vec<u64> = Vec::with_capacity(messages.len());
     let mut stack_series = empty_vec.clone();
@@ -504,9 +1207,21 @@ fn graph_memory(messages: Vec<Message>, graph_faults: bool, out: PathBuf) {
     let mut vvar_series = empty_vec.clone();
     let mut vsyscall_series = empty_vec.clone();
     let mut vsys_series = empty_vec.clone();
+    let mut named_anon_series = empty_vec.clone();
+    let mut anon_shmem_series = empty_vec.clone();
+    let mut memfd_series = empty_vec.clone();
+    let mut hugetlb_series = empty_vec.clone();
+    let mut deleted_series = empty_vec.clone();
+    let mut custom_series: Vec<Vec<u64>> = categories.iter().map(|_| empty_vec.clone()).collect();
+    let mut unclassified_series = empty_vec.clone();
+    // Swap is stacked on top of the metric, unless the metric is already about swap
+    let mut swap_series = match metric {
+        Metric::Swap | Metric::SwapPss => None,
+        _ => Some(empty_vec.clone()),
+    };
     // want a BTreeMap here to make the order of categories as consistent as possible in final graph
     let mut other_series = BTreeMap::new();
-    let mut faults_series = graph_faults.then(|| empty_vec.clone());
+    let mut faults_series = args.graph_faults.then(|| empty_vec.clone());
     let mut zero_series = Vec::new();
     let mut xs: Vec<f64> = Vec::with_capacity(messages.len());
     for message in messages {
@@ -532,7 +1247,24 @@ fn graph_memory(messages: Vec<Message>, graph_faults: bool, out: PathBuf) {
         vvar_series.push(all.vvar);
         vsyscall_series.push(all.vsyscall);
         vsys_series.push(all.sysv_shm);
+        named_anon_series.push(all.named_anon.values().sum());
+        anon_shmem_series.push(all.anon_shmem.values().sum());
+        memfd_series.push(all.memfd.values().sum());
+        hugetlb_series.push(all.hugetlb.values().sum());
+        deleted_series.push(all.deleted_files);
+        for (series, &category) in custom_series.iter_mut().zip(categories) {
+            series.push(all.custom.get(category).copied().unwrap_or(0));
+        }
+        unclassified_series.push(all.unclassified);
+        if let Some(swap_series) = &mut swap_series {
+            swap_series.push(if metric == Metric::Pss {
+                all.swap_pss
+            } else {
+                all.swap
+            });
+        }
         for (path, pss) in all.other {
+            // a path that shows up late has been 0 until then
             other_series
                 .entry(path)
                 .or_insert(zero_series.clone())
@@ -541,11 +1273,94 @@ fn graph_memory(messages: Vec<Message>, graph_faults: bool, out: PathBuf) {
 
         zero_series.push(0);
     }
+    // and a path that goes away is 0 from then on
+    for series in other_series.values_mut() {
+        series.resize(zero_series.len(), 0);
+    }
+
+    let y_label = if swap_series.is_some() {
+        format!("Total {} + Swap", metric.long_name())
+    } else {
+        format!("Total {}", metric.long_name())
+    };
+    let mut layers: Vec<Layer> = Vec::new();
+    let mut add_layer = |values: Vec<u64>, label: &str| {
+        let label = if values.iter().any(|&v| v != 0) {
+            label.to_string()
+        } else {
+            format!("{label} (unused)")
+        };
+        let color = PALETTE[layers.len() % PALETTE.len()];
+        layers.push(Layer {
+            label,
+            color,
+            values,
+        });
+    };
+
+    add_layer(stack_series, "Stack");
+    add_layer(heap_series, "Heap");
+    add_layer(thread_stack_series, "Thread Stack");
+    add_layer(bin_text_series, "Binary Text");
+    add_layer(lib_text_series, "External Text");
+    add_layer(bin_data_series, "Binary Data");
+    add_layer(lib_data_series, "External Data");
+    add_layer(anon_map_series, "Anonymous Mappings");
+    add_layer(vdso_series, "vDSO");
+    add_layer(vvar_series, "vvar");
+    add_layer(vsyscall_series, "vsyscall");
+    add_layer(vsys_series, "SystemV Shared Memory");
+    add_layer(named_anon_series, "Named Anonymous Mappings");
+    add_layer(anon_shmem_series, "Shared Anonymous Mappings");
+    add_layer(memfd_series, "memfd Files");
+    add_layer(hugetlb_series, "hugetlbfs Mappings");
+    add_layer(deleted_series, "Deleted Files");
+    for (series, category) in custom_series.into_iter().zip(categories) {
+        add_layer(series, category);
+    }
+    for (path, series) in other_series {
+        add_layer(series, &path);
+    }
+    add_layer(unclassified_series, "Unclassified");
+    if let Some(swap_series) = swap_series {
+        let label = if metric == Metric::Pss {
+            "Swapped Out (SwapPss)"
+        } else {
+            "Swapped Out (Swap)"
+        };
+        add_layer(swap_series, label);
+    }
+    let chart = Chart {
+        times: xs,
+        layers,
+        y_label,
+        faults: faults_series,
+        faults_label: "Major+Minor Page Faults".to_string(),
+    };
+    #[cfg(feature = "gnuplot")]
+    if args.gnuplot {
+        gnuplot_chart(&chart, out);
+        return Ok(());
+    }
+    chart.save(out, 1024, 768)
+}
+
+/// Draws `chart` with gnuplot, in the way that the graph was drawn before it could be drawn
+/// without gnuplot.
+#[cfg(feature = "gnuplot")]
+fn gnuplot_chart(chart: &Chart, out: &Path) {
+    use gnuplot::TickOption::Mirror;
+    use gnuplot::XAxis::X1;
+    use gnuplot::YAxis::Y2;
+    use gnuplot::{
+        AutoOption::*, AxesCommon, Coordinate::*, DashType::*, Figure, LegendOption::*,
+        PlotOption::*, RGBString,
+    };
 
     let to_kb = |val: u64| (val as f32) / 1000.0;
     let mut fg = Figure::new();
     let axes = fg.axes2d();
-    let x_len = (zero_series.len() - 1) as f64 / 0.75; // hack to make legend appear outside of chart area :(
+    let x_len = (chart.times.len() - 1) as f64 / 0.75; // hack to make legend appear outside of chart area :(
     axes.set_x_range(Fix(0.0), Fix(x_len))
         .set_y_ticks(Some((Auto, 4)), &[Mirror(false)], &[])
         .set_y_grid(true)
@@ -554,59 +1369,33 @@ fn graph_memory(messages: Vec<Message>, graph_faults: bool, out: PathBuf) {
         .set_minor_grid_options(&[LineStyle(Solid)])
         .set_legend(Graph(1.0), Graph(1.0), &[Invert], &[])
         .set_x_label("Time (s)", &[])
-        .set_y_label("Total Proportional Set Size (KB)", &[]);
-    if faults_series.is_some() {
+        .set_y_label(&format!("{} (KB)", chart.y_label), &[]);
+    if chart.faults.is_some() {
         axes.set_y2_ticks(Some((Auto, 4)), &[], &[])
-            .set_y2_label("Major+Minor Page Faults", &[]);
-    }
-    let first_series = vec![0.0; zero_series.len()];
-    let mut prev_series = first_series;
-    let mut i = 0;
-    let mut draw_series = |series: &Vec<u64>, label: &str| {
-        let mut is_used = false;
-        let series = prev_series
+            .set_y2_label(&chart.faults_label, &[]);
+    }
+    let mut prev_series = vec![0.0; chart.times.len()];
+    for layer in &chart.layers {
+        let series: Vec<f32> = prev_series
             .iter()
-            .zip(series)
-            .map(|(a, b)| {
-                if *b != 0 {
-                    is_used = true;
-                }
-                a + to_kb(*b)
-            })
+            .zip(&layer.values)
+            .map(|(a, b)| a + to_kb(*b))
             .collect();
-        let label = if is_used {
-            label
-        } else {
-            &format!("{label} (unused)")
-        };
-        let label = &label.replace("_", "\\_"); // escape LaTeX _
+        let label = &layer.label.replace("_", "\\_"); // escape LaTeX _
         axes.fill_between(
-            &xs,
+            &chart.times,
             &prev_series,
             &series,
-            &[Caption(label), FillAlpha(0.7), Color(PALETTE[i].clone())],
+            &[
+                Caption(label),
+                FillAlpha(0.7),
+                Color(RGBString(layer.color)),
+            ],
         );
         prev_series = series;
-        i = (i + 1) % PALETTE.len();
-    };
-
-    draw_series(&stack_series, "Stack");
-    draw_series(&heap_series, "Heap");
-    draw_series(&thread_stack_series, "Thread Stack");
-    draw_series(&bin_text_series, "Binary Text");
-    draw_series(&lib_text_series, "External Text");
-    draw_series(&bin_data_series, "Binary Data");
-    draw_series(&lib_data_series, "External Data");
-    draw_series(&anon_map_series, "Anonymous Mappings");
-    draw_series(&vdso_series, "vDSO");
-    draw_series(&vvar_series, "vvar");
-    draw_series(&vsyscall_series, "vsyscall");
-    draw_series(&vsys_series, "SystemV Shared Memory");
-    for (path, series) in other_series {
-        draw_series(&series, &path);
     }
-    if let Some(faults_series) = faults_series {
-        axes.lines(&xs, &faults_series, &[Axes(X1, Y2)]);
+    if let Some(faults_series) = &chart.faults {
+        axes.lines(&chart.times, faults_series, &[Axes(X1, Y2)]);
     }
     /*
     let last_series = prev_series;
@@ -622,5 +1411,5 @@ fn graph_memory(messages: Vec<Message>, graph_faults: bool, out: PathBuf) {
     let rmedian_idx = if max_idx == last_series.len() {None} else {Some(get_median_idx(iter.clone(), max_idx..=last_series.len()))};
     */
 
-    fg.save_to_svg(out.as_path(), 1024, 768).unwrap();
+    fg.save_to_svg(out, 1024, 768).unwrap();
 }
@@ -0,0 +1,380 @@
+/* Copyright 2025 Andrew Riachi
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, version 3 only.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Exports the latest sample as metrics, served over HTTP for Prometheus to scrape and written to
+//! a file for the textfile collector of node_exporter.
+
+use crate::SimpleMemory;
+use log::warn;
+use smaps_profiler::{perms_string, FMask, Metric, ProcListing};
+use std::collections::HashMap;
+use std::fmt::Write as _;
+use std::fs;
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+const OPENMETRICS_TYPE: &str = "application/openmetrics-text; version=1.0.0; charset=utf-8";
+const TEXT_TYPE: &str = "text/plain; version=0.0.4; charset=utf-8";
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Kind {
+    Gauge,
+    Counter,
+}
+
+/// The samples of one metric, with everything but the labels and the value in common
+struct Family {
+    /// Without the "_total" of a counter
+    name: &'static str,
+    help: &'static str,
+    kind: Kind,
+    samples: Vec<(String, u64)>,
+}
+
+impl Family {
+    fn new(name: &'static str, kind: Kind, help: &'static str) -> Family {
+        Family {
+            name,
+            help,
+            kind,
+            samples: Vec::new(),
+        }
+    }
+}
+
+pub struct Exporter {
+    families: Arc<Mutex<Vec<Family>>>,
+    textfile: Option<PathBuf>,
+    file_mask: Option<FMask>,
+}
+
+impl Exporter {
+    /// Starts serving /metrics on `listen`, if given, from a thread of its own, and checks that
+    /// the textfile can be written.
+    pub fn new(
+        listen: Option<&str>,
+        textfile: Option<PathBuf>,
+        file_mask: Option<FMask>,
+    ) -> io::Result<Exporter> {
+        let families = Arc::new(Mutex::new(Vec::new()));
+        if let Some(addr) = listen {
+            let listener = TcpListener::bind(addr).map_err(|e| {
+                io::Error::new(e.kind(), format!("Failed to listen on {addr}: {e}"))
+            })?;
+            let families = Arc::clone(&families);
+            thread::spawn(move || serve(listener, families));
+        }
+        let exporter = Exporter {
+            families,
+            textfile,
+            file_mask,
+        };
+        // with no families, which node_exporter reads as no metrics, until the first sample
+        exporter.write_textfile(&[])?;
+        Ok(exporter)
+    }
+
+    /// Replaces what is served with `procs`, and writes it to the textfile.
+    pub fn update(
+        &self,
+        procs: &[ProcListing],
+        metric: Metric,
+        categories: &[&str],
+    ) -> io::Result<()> {
+        let families = self.families(procs, metric, categories);
+        self.write_textfile(&families)?;
+        *self.families.lock().unwrap() = families;
+        Ok(())
+    }
+
+    fn write_textfile(&self, families: &[Family]) -> io::Result<()> {
+        let Some(path) = &self.textfile else {
+            return Ok(());
+        };
+        // node_exporter only reads files that end in .prom, so it never sees a partial one
+        let mut tmp = path.clone().into_os_string();
+        tmp.push(".tmp");
+        fs::write(&tmp, render(families, false))
+            .and_then(|()| fs::rename(&tmp, path))
+            .map_err(|e| {
+                let path = path.display();
+                io::Error::new(e.kind(), format!("Failed to write textfile {path}: {e}"))
+            })
+    }
+
+    fn families(&self, procs: &[ProcListing], metric: Metric, categories: &[&str]) -> Vec<Family> {
+        let mut memory = Family::new(
+            "smaps_profiler_memory_bytes",
+            Kind::Gauge,
+            "Memory of the process in each category, as counted by the smaps field in the metric label",
+        );
+        let mut swap = Family::new(
+            "smaps_profiler_swap_bytes",
+            Kind::Gauge,
+            "Swap of the process in each category",
+        );
+        let mut swap_pss = Family::new(
+            "smaps_profiler_swap_pss_bytes",
+            Kind::Gauge,
+            "SwapPss of the process in each category",
+        );
+        let mut files = Family::new(
+            "smaps_profiler_file_bytes",
+            Kind::Gauge,
+            "Memory of the file-backed mappings of the process, added up by file",
+        );
+        let mut minor_faults = Family::new(
+            "smaps_profiler_minor_faults",
+            Kind::Counter,
+            "Minor page faults of the process since it started",
+        );
+        let mut major_faults = Family::new(
+            "smaps_profiler_major_faults",
+            Kind::Counter,
+            "Major page faults of the process since it started",
+        );
+        for proc in procs {
+            let pid = proc.pid.to_string();
+            let process = [
+                ("pid", pid.as_str()),
+                ("comm", proc.comm.as_str()),
+                ("cmdline", proc.cmdline.as_str()),
+            ];
+            let mem = SimpleMemory::new(&proc.memory_ext, metric, categories);
+            for (category, bytes) in mem.by_category(categories) {
+                let memory_labels = labels(
+                    &process,
+                    &[("metric", metric.smaps_name()), ("category", category)],
+                );
+                memory.samples.push((memory_labels, bytes));
+                let labels = labels(&process, &[("category", category)]);
+                let swapped = |by_category: &HashMap<String, u64>| {
+                    by_category.get(category).copied().unwrap_or(0)
+                };
+                swap.samples
+                    .push((labels.clone(), swapped(&mem.swap_by_category)));
+                swap_pss
+                    .samples
+                    .push((labels, swapped(&mem.swap_pss_by_category)));
+            }
+            if let Some(mask) = &self.file_mask {
+                for (file, stats) in proc.memory_ext.aggregate_file_maps(mask) {
+                    let path = file.path.as_ref().map(|p| p.to_string_lossy().into_owned());
+                    let perms = perms_string::to_string(file.masked_perms);
+                    let mut extra = vec![("metric", metric.smaps_name())];
+                    if let Some(path) = &path {
+                        extra.push(("path", path));
+                    }
+                    extra.push(("perms", &perms));
+                    if let Some(is_self) = file.is_self {
+                        extra.push(("self", if is_self { "true" } else { "false" }));
+                    }
+                    files
+                        .samples
+                        .push((labels(&process, &extra), stats.get(metric)));
+                }
+            }
+            minor_faults
+                .samples
+                .push((labels(&process, &[]), proc.faults.minor));
+            major_faults
+                .samples
+                .push((labels(&process, &[]), proc.faults.major));
+        }
+        let mut families = vec![memory, swap, swap_pss];
+        if self.file_mask.is_some() {
+            families.push(files);
+        }
+        families.extend([minor_faults, major_faults]);
+        families
+    }
+}
+
+/// Formats `process` and `extra` as the labels of a sample, e.g. `{pid="1",comm="init"}`.
+fn labels(process: &[(&str, &str)], extra: &[(&str, &str)]) -> String {
+    let mut s = String::from("{");
+    for (i, (name, value)) in process.iter().chain(extra).enumerate() {
+        if i > 0 {
+            s.push(',');
+        }
+        write!(s, "{name}=\"").unwrap();
+        for c in value.chars() {
+            match c {
+                '\\' => s.push_str("\\\\"),
+                '"' => s.push_str("\\\""),
+                '\n' => s.push_str("\\n"),
+                c => s.push(c),
+            }
+        }
+        s.push('"');
+    }
+    s.push('}');
+    s
+}
+
+/// Writes `families` in the OpenMetrics text format, or in the older Prometheus text format that
+/// node_exporter reads. They differ in how counters are named and in the "# EOF" at the end.
+fn render(families: &[Family], openmetrics: bool) -> String {
+    let mut s = String::new();
+    for family in families {
+        let (kind, sample_name) = match family.kind {
+            Kind::Gauge => ("gauge", family.name.to_string()),
+            Kind::Counter => ("counter", format!("{}_total", family.name)),
+        };
+        let name = if openmetrics {
+            family.name
+        } else {
+            &sample_name
+        };
+        writeln!(s, "# TYPE {name} {kind}").unwrap();
+        if openmetrics && family.name.ends_with("_bytes") {
+            writeln!(s, "# UNIT {name} bytes").unwrap();
+        }
+        writeln!(s, "# HELP {name} {}.", family.help).unwrap();
+        for (labels, value) in &family.samples {
+            writeln!(s, "{sample_name}{labels} {value}").unwrap();
+        }
+    }
+    if openmetrics {
+        s.push_str("# EOF\n");
+    }
+    s
+}
+
+fn serve(listener: TcpListener, families: Arc<Mutex<Vec<Family>>>) {
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(e) => {
+                warn!("couldn't accept a scrape: {e}");
+                continue;
+            }
+        };
+        // each on its own thread, so that a client that never sends its request doesn't hold up
+        // the others until it times out
+        let families = Arc::clone(&families);
+        thread::spawn(move || {
+            if let Err(e) = respond(stream, &families) {
+                warn!("couldn't answer a scrape: {e}");
+            }
+        });
+    }
+}
+
+/// Answers one HTTP request, with OpenMetrics if the client accepts it.
+fn respond(mut stream: TcpStream, families: &Mutex<Vec<Family>>) -> io::Result<()> {
+    stream.set_read_timeout(Some(Duration::from_secs(10)))?;
+    let mut reader = BufReader::new(&stream);
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+    let mut openmetrics = false;
+    loop {
+        let mut header = String::new();
+        if reader.read_line(&mut header)? == 0 || header.trim().is_empty() {
+            break;
+        }
+        if let Some((name, value)) = header.split_once(':') {
+            if name.eq_ignore_ascii_case("accept") && value.contains("application/openmetrics-text")
+            {
+                openmetrics = true;
+            }
+        }
+    }
+    let mut parts = request_line.split_whitespace();
+    let method = parts.next().unwrap_or_default();
+    let path = parts.next().unwrap_or_default();
+    let path = path.split_once('?').map_or(path, |(path, _)| path);
+    let (status, content_type, body) = match (method, path) {
+        ("GET" | "HEAD", "/metrics") => {
+            let families = families.lock().unwrap();
+            let content_type = if openmetrics {
+                OPENMETRICS_TYPE
+            } else {
+                TEXT_TYPE
+            };
+            ("200 OK", content_type, render(&families, openmetrics))
+        }
+        ("GET" | "HEAD", _) => (
+            "404 Not Found",
+            "text/plain",
+            "Metrics are at /metrics\n".to_string(),
+        ),
+        _ => (
+            "405 Method Not Allowed",
+            "text/plain",
+            "Only GET and HEAD are allowed\n".to_string(),
+        ),
+    };
+    write!(
+        stream,
+        "HTTP/1.1 {status}\r\nContent-Type: {content_type}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        body.len()
+    )?;
+    if method != "HEAD" {
+        stream.write_all(body.as_bytes())?;
+    }
+    stream.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn labels_are_escaped() {
+        let labels = labels(
+            &[("pid", "1"), ("cmdline", "sh -c \"echo \\n\"\nexit")],
+            &[("category", "heap")],
+        );
+        assert_eq!(
+            labels,
+            r#"{pid="1",cmdline="sh -c \"echo \\n\"\nexit",category="heap"}"#
+        );
+    }
+
+    #[test]
+    fn counters_are_named_for_each_format() {
+        let mut faults = Family::new("smaps_profiler_minor_faults", Kind::Counter, "Faults");
+        faults.samples.push((labels(&[("pid", "1")], &[]), 7));
+        let mut memory = Family::new("smaps_profiler_memory_bytes", Kind::Gauge, "Memory");
+        memory.samples.push((labels(&[("pid", "1")], &[]), 4096));
+        let families = [memory, faults];
+
+        assert_eq!(
+            render(&families, false),
+            "# TYPE smaps_profiler_memory_bytes gauge\n\
+             # HELP smaps_profiler_memory_bytes Memory.\n\
+             smaps_profiler_memory_bytes{pid=\"1\"} 4096\n\
+             # TYPE smaps_profiler_minor_faults_total counter\n\
+             # HELP smaps_profiler_minor_faults_total Faults.\n\
+             smaps_profiler_minor_faults_total{pid=\"1\"} 7\n"
+        );
+        assert_eq!(
+            render(&families, true),
+            "# TYPE smaps_profiler_memory_bytes gauge\n\
+             # UNIT smaps_profiler_memory_bytes bytes\n\
+             # HELP smaps_profiler_memory_bytes Memory.\n\
+             smaps_profiler_memory_bytes{pid=\"1\"} 4096\n\
+             # TYPE smaps_profiler_minor_faults counter\n\
+             # HELP smaps_profiler_minor_faults Faults.\n\
+             smaps_profiler_minor_faults_total{pid=\"1\"} 7\n\
+             # EOF\n"
+        );
+    }
+}
@@ -0,0 +1,359 @@
+/* Copyright 2025 Andrew Riachi
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, version 3 only.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Sends every message to a time series database, as InfluxDB line protocol or as StatsD gauges.
+
+use crate::{Message, SimpleMemory};
+use clap::ValueEnum;
+use log::warn;
+use smaps_profiler::{Faults, Metric};
+use std::collections::BTreeMap;
+use std::env;
+use std::fmt::Write as _;
+use std::fs::OpenOptions;
+use std::io::{self, Write};
+use std::net::UdpSocket;
+use std::sync::mpsc::{self, SyncSender, TrySendError};
+use std::thread;
+use std::time::{Duration, SystemTime};
+use ureq::Agent;
+
+/// What a series can be tagged with. The memory of everything that isn't told apart by the tags
+/// is added up.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, ValueEnum)]
+pub enum Tag {
+    Pid,
+    Comm,
+    Cgroup,
+    Category,
+}
+
+impl Tag {
+    fn name(self) -> &'static str {
+        match self {
+            Tag::Pid => "pid",
+            Tag::Comm => "comm",
+            Tag::Cgroup => "cgroup",
+            Tag::Category => "category",
+        }
+    }
+}
+
+/// The values of the tags of a series, in the order of `--tags`. A tag that doesn't apply, like
+/// the cgroup of a process that isn't in one, is left out.
+type TagSet = Vec<(Tag, String)>;
+
+/// A process, or a cgroup with --by-cgroup
+struct Source<'a> {
+    pid: Option<i32>,
+    comm: Option<&'a str>,
+    cgroup: Option<&'a str>,
+    faults: Faults,
+    memory: &'a SimpleMemory,
+}
+
+impl Source<'_> {
+    fn tags(&self, tags: &[Tag], category: Option<&str>) -> TagSet {
+        tags.iter()
+            .filter_map(|&tag| {
+                let value = match tag {
+                    Tag::Pid => self.pid?.to_string(),
+                    Tag::Comm => self.comm?.to_string(),
+                    Tag::Cgroup => self.cgroup?.to_string(),
+                    Tag::Category => category?.to_string(),
+                };
+                // comm is empty in recordings from before it was recorded
+                (!value.is_empty()).then_some((tag, value))
+            })
+            .collect()
+    }
+}
+
+/// The memory and faults of every series in `message`
+struct Series {
+    memory: BTreeMap<TagSet, u64>,
+    /// Tagged like `memory`, but without the category
+    faults: BTreeMap<TagSet, (u64, u64)>,
+}
+
+impl Series {
+    fn new(message: &Message, categories: &[&str], tags: &[Tag]) -> Series {
+        let procs = message.procs.iter().map(|proc| Source {
+            pid: Some(proc.pid),
+            comm: Some(&proc.comm),
+            cgroup: proc.cgroup.as_deref(),
+            faults: proc.faults,
+            memory: &proc.memory,
+        });
+        let cgroups = message.cgroups.iter().map(|group| Source {
+            pid: None,
+            comm: None,
+            cgroup: group.cgroup.as_deref(),
+            faults: group.faults,
+            memory: &group.memory,
+        });
+        let mut series = Series {
+            memory: BTreeMap::new(),
+            faults: BTreeMap::new(),
+        };
+        for source in procs.chain(cgroups) {
+            let by_category = source.memory.by_category(categories);
+            if tags.contains(&Tag::Category) {
+                for (category, bytes) in by_category {
+                    let key = source.tags(tags, Some(category));
+                    *series.memory.entry(key).or_default() += bytes;
+                }
+            } else {
+                let total = by_category.iter().map(|&(_, bytes)| bytes).sum::<u64>();
+                *series.memory.entry(source.tags(tags, None)).or_default() += total;
+            }
+            let sum = series.faults.entry(source.tags(tags, None)).or_default();
+            sum.0 += source.faults.minor;
+            sum.1 += source.faults.major;
+        }
+        series
+    }
+}
+
+/// How many samples can wait to be sent to InfluxDB before new ones are dropped
+const HTTP_BACKLOG: usize = 16;
+
+/// How long one request to InfluxDB can take before it is given up on
+const HTTP_TIMEOUT: Duration = Duration::from_secs(10);
+
+enum InfluxDest {
+    Writer(Box<dyn Write>),
+    /// Lines for the thread that posts them to the URL, so that a slow or unreachable database
+    /// doesn't hold up the sampling
+    Http {
+        url: String,
+        sender: SyncSender<String>,
+    },
+}
+
+/// Writes messages as InfluxDB line protocol, to stdout, to a file, or over HTTP.
+pub struct Influx {
+    dest: InfluxDest,
+    tags: Vec<Tag>,
+}
+
+impl Influx {
+    /// `dest` is "-" for stdout, an http:// or https:// URL, or else the path of a file to append
+    /// to.
+    pub fn new(dest: &str, tags: Vec<Tag>) -> io::Result<Influx> {
+        let dest = if dest == "-" {
+            InfluxDest::Writer(Box::new(io::stdout()))
+        } else if dest.starts_with("http://") || dest.starts_with("https://") {
+            InfluxDest::Http {
+                url: dest.to_string(),
+                sender: spawn_poster(dest.to_string(), env::var("INFLUX_TOKEN").ok()),
+            }
+        } else {
+            let file = OpenOptions::new().create(true).append(true).open(dest)?;
+            InfluxDest::Writer(Box::new(file))
+        };
+        Ok(Influx { dest, tags })
+    }
+
+    pub fn send(
+        &mut self,
+        message: &Message,
+        metric: Metric,
+        categories: &[&str],
+    ) -> io::Result<()> {
+        let series = Series::new(message, categories, &self.tags);
+        let timestamp = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos();
+        let field = escape_influx(&metric.smaps_name().to_ascii_lowercase());
+        let mut lines = String::new();
+        for (tags, bytes) in &series.memory {
+            writeln!(
+                lines,
+                "smaps_profiler{} {field}={bytes}i {timestamp}",
+                influx_tags(tags)
+            )
+            .unwrap();
+        }
+        for (tags, (minor, major)) in &series.faults {
+            writeln!(
+                lines,
+                "smaps_profiler_faults{} minor={minor}i,major={major}i {timestamp}",
+                influx_tags(tags)
+            )
+            .unwrap();
+        }
+        match &mut self.dest {
+            InfluxDest::Writer(writer) => {
+                writer.write_all(lines.as_bytes())?;
+                writer.flush()
+            }
+            InfluxDest::Http { url, sender } => {
+                // the database being down for a moment shouldn't stop the profiling
+                if let Err(TrySendError::Full(_)) = sender.try_send(lines) {
+                    warn!("dropped a sample because {url} is falling behind");
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Starts a thread that posts every batch of lines it is sent to `url`, one at a time.
+fn spawn_poster(url: String, token: Option<String>) -> SyncSender<String> {
+    let (sender, receiver) = mpsc::sync_channel::<String>(HTTP_BACKLOG);
+    thread::spawn(move || {
+        let agent: Agent = Agent::config_builder()
+            .timeout_global(Some(HTTP_TIMEOUT))
+            .build()
+            .new_agent();
+        for lines in receiver {
+            let mut request = agent.post(url.as_str());
+            if let Some(token) = &token {
+                request = request.header("Authorization", format!("Token {token}"));
+            }
+            if let Err(e) = request
+                .content_type("text/plain; charset=utf-8")
+                .send(lines)
+            {
+                warn!("couldn't send a sample to {url}: {e}");
+            }
+        }
+    });
+    sender
+}
+
+/// The tags of a line, sorted by key as InfluxDB prefers, each with the comma before it
+fn influx_tags(tags: &TagSet) -> String {
+    let mut tags: Vec<_> = tags
+        .iter()
+        .map(|(tag, value)| (tag.name(), value))
+        .collect();
+    tags.sort_unstable();
+    tags.into_iter()
+        .map(|(key, value)| format!(",{key}={}", escape_influx(value)))
+        .collect()
+}
+
+/// Escapes a tag value or a field key of line protocol.
+fn escape_influx(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            ',' | '=' | ' ' | '\\' => {
+                escaped.push('\\');
+                escaped.push(c);
+            }
+            // a line can't be continued, even escaped
+            '\n' | '\r' => escaped.push(' '),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// The largest datagram to send, which fits in the MTU of most networks
+const MAX_DATAGRAM: usize = 1432;
+
+/// Sends messages as gauges to StatsD, with the tags folded into the metric names, or to
+/// DogStatsD, with the tags as tags.
+pub struct StatsD {
+    socket: UdpSocket,
+    dogstatsd: bool,
+    tags: Vec<Tag>,
+}
+
+impl StatsD {
+    pub fn new(addr: &str, dogstatsd: bool, tags: Vec<Tag>) -> io::Result<StatsD> {
+        let socket = UdpSocket::bind(if addr.starts_with('[') {
+            "[::]:0"
+        } else {
+            "0.0.0.0:0"
+        })?;
+        socket.connect(addr)?;
+        Ok(StatsD {
+            socket,
+            dogstatsd,
+            tags,
+        })
+    }
+
+    pub fn send(&self, message: &Message, metric: Metric, categories: &[&str]) {
+        let series = Series::new(message, categories, &self.tags);
+        let name = format!(
+            "smaps_profiler.{}",
+            metric.smaps_name().to_ascii_lowercase()
+        );
+        let mut lines = Vec::new();
+        for (tags, bytes) in &series.memory {
+            lines.push(self.gauge(&name, tags, *bytes));
+        }
+        for (tags, (minor, major)) in &series.faults {
+            lines.push(self.gauge("smaps_profiler.faults.minor", tags, *minor));
+            lines.push(self.gauge("smaps_profiler.faults.major", tags, *major));
+        }
+        let mut datagram = String::new();
+        for line in lines {
+            if !datagram.is_empty() && datagram.len() + 1 + line.len() > MAX_DATAGRAM {
+                self.send_datagram(&datagram);
+                datagram.clear();
+            }
+            if !datagram.is_empty() {
+                datagram.push('\n');
+            }
+            datagram.push_str(&line);
+        }
+        if !datagram.is_empty() {
+            self.send_datagram(&datagram);
+        }
+    }
+
+    fn gauge(&self, name: &str, tags: &TagSet, value: u64) -> String {
+        if self.dogstatsd {
+            let tags: Vec<String> = tags
+                .iter()
+                .map(|(tag, value)| {
+                    format!("{}:{}", tag.name(), value.replace([',', '|', '\n'], "_"))
+                })
+                .collect();
+            if tags.is_empty() {
+                format!("{name}:{value}|g")
+            } else {
+                format!("{name}:{value}|g|#{}", tags.join(","))
+            }
+        } else {
+            let mut name = name.to_string();
+            for (_, value) in tags {
+                name.push('.');
+                name.extend(value.chars().map(|c| {
+                    if c.is_ascii_alphanumeric() || c == '-' || c == '_' {
+                        c
+                    } else {
+                        '_'
+                    }
+                }));
+            }
+            format!("{name}:{value}|g")
+        }
+    }
+
+    fn send_datagram(&self, datagram: &str) {
+        // UDP is fire and forget anyway, e.g. a missing server can be a refused connection
+        if let Err(e) = self.socket.send(datagram.as_bytes()) {
+            warn!("couldn't send a sample to StatsD: {e}");
+        }
+    }
+}
@@ -0,0 +1,460 @@
+/* Copyright 2025 Andrew Riachi
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, version 3 only.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! A top-like view of the processes that is redrawn every interval instead of printing TSV.
+
+use crate::SimpleMemory;
+use ratatui::crossterm::event::{self, Event, KeyCode, KeyEventKind, KeyModifiers};
+use ratatui::layout::{Constraint, Layout, Rect};
+use ratatui::style::{Modifier, Style, Stylize};
+use ratatui::text::Line;
+use ratatui::widgets::{Block, Cell, Row, Sparkline, Table, TableState};
+use ratatui::{DefaultTerminal, Frame};
+use smaps_profiler::{perms_string, FMask, MMPermissions, MemoryExt, Metric, ProcListing};
+use std::cmp::Reverse;
+use std::collections::{HashMap, VecDeque};
+use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+
+/// Whether the terminal is in raw mode on the alternate screen, and has to be restored before
+/// exiting
+static ACTIVE: AtomicBool = AtomicBool::new(false);
+
+/// Puts the terminal back the way it was, if the TUI took it over. For exiting in a hurry, e.g.
+/// from `process::exit`, which doesn't drop the `Tui`.
+pub fn restore() {
+    if ACTIVE.swap(false, Ordering::Relaxed) {
+        ratatui::restore();
+    }
+}
+
+/// How many samples of the total the sparkline keeps
+const HISTORY: usize = 512;
+
+/// A column of `SimpleMemory`, shown only if some process uses it
+struct Column {
+    name: String,
+    get: Box<dyn Fn(&SimpleMemory) -> u64>,
+}
+
+fn columns(categories: &[String]) -> Vec<Column> {
+    fn column(name: &str, get: impl Fn(&SimpleMemory) -> u64 + 'static) -> Column {
+        Column {
+            name: name.to_string(),
+            get: Box::new(get),
+        }
+    }
+    let mut columns = vec![
+        column("STACK", |m| m.stack),
+        column("HEAP", |m| m.heap),
+        column("THREAD_STACK", |m| m.thread_stack),
+        column("BIN_TEXT", |m| m.bin_text),
+        column("EXTERN_TEXT", |m| m.extern_text),
+        column("BIN_DATA", |m| m.bin_data),
+        column("EXTERN_DATA", |m| m.extern_data),
+        column("ANON_MAP", |m| m.anon_mappings),
+        column("VDSO", |m| m.vdso),
+        column("VVAR", |m| m.vvar),
+        column("VSYSCALL", |m| m.vsyscall),
+        column("SHM", |m| m.sysv_shm),
+        column("NAMED_ANON", |m| m.named_anon.values().sum()),
+        column("ANON_SHMEM", |m| m.anon_shmem.values().sum()),
+        column("MEMFD", |m| m.memfd.values().sum()),
+        column("HUGETLB", |m| m.hugetlb.values().sum()),
+        column("DELETED", |m| m.deleted_files),
+    ];
+    for category in categories {
+        let key = category.clone();
+        columns.push(column(&crate::column_name(category), move |m| {
+            m.custom.get(&key).copied().unwrap_or(0)
+        }));
+    }
+    columns.push(column("OTHER", |m| m.other.values().sum()));
+    columns.push(column("UNCLASSIFIED", |m| m.unclassified));
+    columns.push(column("SWAP", |m| m.swap));
+    columns
+}
+
+/// A process as of the latest sample
+struct Proc {
+    pid: i32,
+    cmdline: String,
+    total: u64,
+    memory: SimpleMemory,
+    memory_ext: MemoryExt,
+    /// Minor and major faults per second since the previous sample
+    fault_rates: (f64, f64),
+}
+
+/// What the rows are sorted by
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum SortKey {
+    Pid,
+    Total,
+    Column(String),
+    MinorFaults,
+    MajorFaults,
+    Cmdline,
+}
+
+struct View {
+    metric: Metric,
+    columns: Vec<Column>,
+    procs: Vec<Proc>,
+    /// The faults of every process at the previous sample, for the rates
+    prev_faults: HashMap<i32, (u64, u64)>,
+    prev_time: Option<Instant>,
+    totals: VecDeque<u64>,
+    sort: SortKey,
+    reverse: bool,
+    /// The pid of the selected process, which stays selected when the rows move
+    selected: Option<i32>,
+    /// The pid of the process whose files are shown
+    expanded: Option<i32>,
+    frozen: bool,
+}
+
+pub struct Tui {
+    terminal: DefaultTerminal,
+    view: View,
+}
+
+impl Tui {
+    pub fn new(metric: Metric, categories: &[&str]) -> io::Result<Tui> {
+        let terminal = ratatui::try_init()?;
+        ACTIVE.store(true, Ordering::Relaxed);
+        let categories: Vec<String> = categories.iter().map(|c| c.to_string()).collect();
+        Ok(Tui {
+            terminal,
+            view: View {
+                metric,
+                columns: columns(&categories),
+                procs: Vec::new(),
+                prev_faults: HashMap::new(),
+                prev_time: None,
+                totals: VecDeque::with_capacity(HISTORY),
+                sort: SortKey::Total,
+                reverse: false,
+                selected: None,
+                expanded: None,
+                frozen: false,
+            },
+        })
+    }
+
+    /// Takes a new sample. The rows only change if the display isn't frozen, but the fault rates
+    /// and the sparkline keep up either way.
+    pub fn update(&mut self, procs: &[ProcListing], categories: &[&str]) {
+        self.view.update(procs, categories);
+    }
+
+    /// Redraws the screen and handles keys until `timeout` has passed. Returns false if the user
+    /// quit.
+    pub fn wait(&mut self, timeout: Duration) -> io::Result<bool> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            self.terminal.draw(|frame| self.view.render(frame))?;
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if !event::poll(remaining)? {
+                return Ok(true);
+            }
+            if let Event::Key(key) = event::read()? {
+                if key.kind != KeyEventKind::Press {
+                    continue;
+                }
+                let ctrl_c =
+                    key.code == KeyCode::Char('c') && key.modifiers.contains(KeyModifiers::CONTROL);
+                if ctrl_c || matches!(key.code, KeyCode::Char('q') | KeyCode::Esc) {
+                    return Ok(false);
+                }
+                self.view.handle_key(key.code);
+            }
+        }
+    }
+}
+
+impl Drop for Tui {
+    fn drop(&mut self) {
+        restore();
+    }
+}
+
+impl View {
+    fn update(&mut self, procs: &[ProcListing], categories: &[&str]) {
+        let now = Instant::now();
+        let elapsed = self
+            .prev_time
+            .map(|t| now.duration_since(t).as_secs_f64())
+            .filter(|&secs| secs > 0.0);
+        let mut faults = HashMap::with_capacity(procs.len());
+        let mut rows = Vec::with_capacity(procs.len());
+        for proc in procs {
+            let current = (proc.faults.minor, proc.faults.major);
+            faults.insert(proc.pid, current);
+            let fault_rates = match (self.prev_faults.get(&proc.pid), elapsed) {
+                (Some(prev), Some(secs)) => (
+                    current.0.saturating_sub(prev.0) as f64 / secs,
+                    current.1.saturating_sub(prev.1) as f64 / secs,
+                ),
+                _ => (0.0, 0.0),
+            };
+            rows.push(Proc {
+                pid: proc.pid,
+                cmdline: proc.cmdline.replace(['\n', '\t'], " "),
+                total: proc.memory_ext.total().get(self.metric),
+                memory: SimpleMemory::new(&proc.memory_ext, self.metric, categories),
+                memory_ext: proc.memory_ext.clone(),
+                fault_rates,
+            });
+        }
+        self.prev_faults = faults;
+        self.prev_time = Some(now);
+        if self.totals.len() == HISTORY {
+            self.totals.pop_front();
+        }
+        self.totals.push_back(rows.iter().map(|p| p.total).sum());
+        if !self.frozen {
+            self.procs = rows;
+            self.sort();
+        }
+    }
+
+    fn sort(&mut self) {
+        let columns = &self.columns;
+        match &self.sort {
+            SortKey::Pid => self.procs.sort_by_key(|p| p.pid),
+            SortKey::Total => self.procs.sort_by_key(|p| Reverse(p.total)),
+            SortKey::Column(name) => {
+                let column = columns.iter().find(|c| &c.name == name).unwrap();
+                self.procs
+                    .sort_by_key(|p| (Reverse((column.get)(&p.memory)), Reverse(p.total)));
+            }
+            SortKey::MinorFaults => self
+                .procs
+                .sort_by(|a, b| b.fault_rates.0.total_cmp(&a.fault_rates.0)),
+            SortKey::MajorFaults => self
+                .procs
+                .sort_by(|a, b| b.fault_rates.1.total_cmp(&a.fault_rates.1)),
+            SortKey::Cmdline => self.procs.sort_by(|a, b| a.cmdline.cmp(&b.cmdline)),
+        }
+        if self.reverse {
+            self.procs.reverse();
+        }
+    }
+
+    /// The columns of `SimpleMemory` that some process uses
+    fn visible_columns(&self) -> Vec<&Column> {
+        self.columns
+            .iter()
+            .filter(|c| self.procs.iter().any(|p| (c.get)(&p.memory) != 0))
+            .collect()
+    }
+
+    /// Every key that the rows can be sorted by, in the order of the columns
+    fn sort_keys(&self) -> Vec<SortKey> {
+        let mut keys = vec![SortKey::Pid, SortKey::Total];
+        keys.extend(
+            self.visible_columns()
+                .into_iter()
+                .map(|c| SortKey::Column(c.name.clone())),
+        );
+        keys.extend([SortKey::MinorFaults, SortKey::MajorFaults, SortKey::Cmdline]);
+        keys
+    }
+
+    fn selected_index(&self) -> Option<usize> {
+        let pid = self.selected?;
+        self.procs.iter().position(|p| p.pid == pid)
+    }
+
+    fn handle_key(&mut self, code: KeyCode) {
+        match code {
+            KeyCode::Up | KeyCode::Char('k') | KeyCode::Down | KeyCode::Char('j') => {
+                if self.procs.is_empty() {
+                    return;
+                }
+                let last = self.procs.len() - 1;
+                let i = match (self.selected_index(), code) {
+                    (None, _) => 0,
+                    (Some(i), KeyCode::Up | KeyCode::Char('k')) => i.saturating_sub(1),
+                    (Some(i), _) => (i + 1).min(last),
+                };
+                self.selected = Some(self.procs[i].pid);
+            }
+            KeyCode::Left | KeyCode::Char('<') | KeyCode::Right | KeyCode::Char('>') => {
+                let keys = self.sort_keys();
+                let i = keys.iter().position(|k| k == &self.sort).unwrap_or(1);
+                let i = match code {
+                    KeyCode::Left | KeyCode::Char('<') => (i + keys.len() - 1) % keys.len(),
+                    _ => (i + 1) % keys.len(),
+                };
+                self.sort = keys[i].clone();
+                self.sort();
+            }
+            KeyCode::Char('r') => {
+                self.reverse = !self.reverse;
+                self.sort();
+            }
+            KeyCode::Enter => {
+                self.expanded = match self.expanded {
+                    Some(pid) if Some(pid) == self.selected => None,
+                    _ => self.selected,
+                };
+            }
+            KeyCode::Char('f') | KeyCode::Char(' ') => self.frozen = !self.frozen,
+            _ => {}
+        }
+    }
+
+    fn render(&self, frame: &mut Frame) {
+        let expanded = self
+            .expanded
+            .and_then(|pid| self.procs.iter().find(|p| p.pid == pid));
+        let [header, sparkline, table, files, footer] = Layout::vertical([
+            Constraint::Length(1),
+            Constraint::Length(5),
+            Constraint::Min(5),
+            Constraint::Percentage(if expanded.is_some() { 40 } else { 0 }),
+            Constraint::Length(1),
+        ])
+        .areas(frame.area());
+
+        let sort_name = match &self.sort {
+            SortKey::Pid => "PID",
+            SortKey::Total => "TOTAL",
+            SortKey::Column(name) => name,
+            SortKey::MinorFaults => "MINFLT/s",
+            SortKey::MajorFaults => "MAJFLT/s",
+            SortKey::Cmdline => "CMD",
+        };
+        let mut title = format!(
+            "smaps-profiler: {} processes, {} sorted by {sort_name}{}",
+            self.procs.len(),
+            self.metric,
+            if self.reverse { " (reversed)" } else { "" }
+        );
+        if self.frozen {
+            title.push_str("  [FROZEN]");
+        }
+        frame.render_widget(Line::from(title).bold(), header);
+
+        let width = sparkline.width.saturating_sub(2) as usize;
+        let skip = self.totals.len().saturating_sub(width);
+        let latest = self.totals.back().copied().unwrap_or(0);
+        let peak = self.totals.iter().copied().max().unwrap_or(0);
+        frame.render_widget(
+            Sparkline::default()
+                .block(Block::bordered().title(format!(
+                    "Total {}: {} (peak {})",
+                    self.metric,
+                    human(latest),
+                    human(peak)
+                )))
+                .data(self.totals.iter().skip(skip).copied()),
+            sparkline,
+        );
+
+        self.render_table(frame, table);
+        if let Some(proc) = expanded {
+            render_files(frame, files, proc, self.metric);
+        }
+        frame.render_widget(
+            Line::from("↑↓ select  ←→ sort  r reverse  Enter files  f freeze  q quit").reversed(),
+            footer,
+        );
+    }
+
+    fn render_table(&self, frame: &mut Frame, area: Rect) {
+        let columns = self.visible_columns();
+        let mut names = vec!["PID".to_string(), "TOTAL".to_string()];
+        names.extend(columns.iter().map(|c| c.name.clone()));
+        names.extend(["MINFLT/s", "MAJFLT/s", "CMD"].map(String::from));
+        let sort_keys = self.sort_keys();
+        let header = Row::new(names.iter().zip(&sort_keys).map(|(name, key)| {
+            let cell = Cell::from(name.as_str());
+            if key == &self.sort {
+                cell.add_modifier(Modifier::UNDERLINED)
+            } else {
+                cell
+            }
+        }))
+        .bold();
+        let rows = self.procs.iter().map(|p| {
+            let mut cells = vec![p.pid.to_string(), human(p.total)];
+            cells.extend(columns.iter().map(|c| human((c.get)(&p.memory))));
+            cells.push(format!("{:.0}", p.fault_rates.0));
+            cells.push(format!("{:.0}", p.fault_rates.1));
+            cells.push(p.cmdline.clone());
+            Row::new(cells)
+        });
+        let mut widths: Vec<Constraint> = names[..names.len() - 1]
+            .iter()
+            .map(|name| Constraint::Length(name.len().max(7) as u16))
+            .collect();
+        widths.push(Constraint::Fill(1));
+        let table = Table::new(rows, widths)
+            .header(header)
+            .row_highlight_style(Style::new().reversed());
+        let mut state = TableState::new().with_selected(self.selected_index());
+        frame.render_stateful_widget(table, area, &mut state);
+    }
+}
+
+/// The file-backed mappings of `proc`, added up by path and permissions
+fn render_files(frame: &mut Frame, area: Rect, proc: &Proc, metric: Metric) {
+    let mask = FMask::new(false, true, MMPermissions::all());
+    let mut files: Vec<_> = proc
+        .memory_ext
+        .aggregate_file_maps(&mask)
+        .into_iter()
+        .map(|(file, stats)| (stats.get(metric), file))
+        .collect();
+    files.sort_unstable_by(|(l, lf), (r, rf)| (Reverse(l), &lf.path).cmp(&(Reverse(r), &rf.path)));
+    let total = proc.total.max(1);
+    let rows = files.into_iter().map(|(bytes, file)| {
+        let path = file
+            .path
+            .as_ref()
+            .map_or_else(String::new, |p| p.display().to_string());
+        Row::new([
+            human(bytes),
+            format!("{:.1}%", bytes as f64 * 100.0 / total as f64),
+            perms_string::to_string(file.masked_perms),
+            path,
+        ])
+    });
+    let widths = [
+        Constraint::Length(7),
+        Constraint::Length(6),
+        Constraint::Length(5),
+        Constraint::Fill(1),
+    ];
+    let table = Table::new(rows, widths)
+        .header(Row::new([metric.smaps_name(), "%", "PERMS", "FILE"]).bold())
+        .block(Block::bordered().title(format!("Files of PID {} ({})", proc.pid, proc.cmdline)));
+    frame.render_widget(table, area);
+}
+
+/// Bytes with a unit, in as few characters as possible
+fn human(bytes: u64) -> String {
+    const UNITS: [(&str, f64); 3] = [("G", 1e9), ("M", 1e6), ("K", 1e3)];
+    for (unit, size) in UNITS {
+        if bytes as f64 >= size {
+            return format!("{:.1}{unit}", bytes as f64 / size);
+        }
+    }
+    bytes.to_string()
+}
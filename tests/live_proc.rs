@@ -0,0 +1,42 @@
+/* Copyright 2025 Andrew Riachi
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, version 3 only.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Reads the live /proc, where the process doing the reading is left out.
+
+use smaps_profiler::{
+    get_processes_with_root, Action, ErrorKind, ErrorPolicy, Selection, Selector, PROC_ROOT,
+};
+use std::path::Path;
+use std::process::Command;
+
+#[test]
+fn launched_command_has_a_parent() {
+    let mut child = Command::new("sleep").arg("10").spawn().unwrap();
+    let pid = child.id() as i32;
+    let policy = ErrorPolicy::default().with(ErrorKind::MissingParent, Action::Fail);
+    let selection = Selection {
+        include: Some(Selector::Pid(pid)),
+        match_children: true,
+        ..Selection::default()
+    };
+    let result = get_processes_with_root(Path::new(PROC_ROOT), &selection, &policy);
+    child.kill().unwrap();
+    child.wait().unwrap();
+
+    let procs = result.unwrap();
+    let pids: Vec<i32> = procs.iter().map(|p| p.pid).collect();
+    assert_eq!(pids, [pid]);
+    assert_eq!(procs[0].ppid, std::process::id() as i32);
+}
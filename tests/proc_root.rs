@@ -0,0 +1,176 @@
+/* Copyright 2025 Andrew Riachi
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, version 3 only.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Reads the processes in tests/fixtures/proc, a copy of /proc with three processes: 4242, whose
+//! smaps has one mapping of each kind, its child 4243, and 4244, whose smaps has the mappings that
+//! get a category of their own, like "[anon:foo]" and "/memfd:x (deleted)".
+
+use smaps_profiler::{
+    get_processes_with_root, get_smaps, get_smaps_with, ErrorPolicy, FMask, FileMapping,
+    MMPermissions, MaskedFileMapping, MemStats, Metric, ProcListing, Rules, Selection, Selector,
+    SmapsOptions,
+};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+const KB: u64 = 1024;
+
+fn fixture_root() -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/proc")
+}
+
+fn read(selection: &Selection) -> Vec<ProcListing> {
+    let policy = ErrorPolicy::default();
+    let procs = get_processes_with_root(&fixture_root(), selection, &policy).unwrap();
+    let mut procs = get_smaps(procs, &policy).unwrap();
+    procs.sort_by_key(|p| p.pid);
+    procs
+}
+
+#[test]
+fn reads_every_process_under_the_root() {
+    let procs = read(&Selection::default());
+    let pids: Vec<i32> = procs.iter().map(|p| p.pid).collect();
+    assert_eq!(pids, [4242, 4243, 4244]);
+
+    let parent = &procs[0];
+    assert_eq!(parent.ppid, 1);
+    assert_eq!(parent.comm, "fixture");
+    assert_eq!(parent.cmdline, "/usr/bin/fixture --serve");
+    assert_eq!(parent.cgroup.as_deref(), Some("/fixture.slice"));
+    assert_eq!((parent.faults.minor, parent.faults.major), (1000, 7));
+}
+
+#[test]
+fn categorizes_the_mappings() {
+    let procs = read(&Selection {
+        include: Some(Selector::Pid(4242)),
+        ..Selection::default()
+    });
+    let [proc] = procs.as_slice() else {
+        panic!("expected only 4242, got {procs:?}");
+    };
+    let mem = &proc.memory_ext;
+    assert_eq!(mem.heap.pss, 200 * KB);
+    assert_eq!(mem.stack.pss, 20 * KB);
+    assert_eq!(mem.anon_map.pss, 64 * KB);
+    assert_eq!(mem.vdso.rss, 4 * KB);
+    assert_eq!(mem.vdso.pss, 0);
+    assert_eq!(mem.thread_stack, Default::default());
+    assert_eq!(mem.unclassified, Default::default());
+    assert!(mem.other_map.is_empty());
+    assert_eq!(mem.file_map.len(), 4);
+
+    // text and data of the binary and of its libraries, as the TSV adds them up
+    let by_kind = mem.aggregate_file_maps(&FMask::new(true, false, MMPermissions::EXECUTE));
+    let pss = |is_self, perms| by_kind[&MaskedFileMapping::new(Some(is_self), None, perms)].pss;
+    assert_eq!(pss(true, MMPermissions::EXECUTE), 16 * KB);
+    assert_eq!(pss(true, MMPermissions::NONE), 8 * KB);
+    assert_eq!(pss(false, MMPermissions::EXECUTE), 100 * KB);
+    assert_eq!(pss(false, MMPermissions::NONE), 12 * KB);
+
+    let total = mem.total();
+    assert_eq!(total.get(Metric::Pss), 420 * KB);
+    assert_eq!(total.get(Metric::Rss), 724 * KB);
+    assert_eq!(total.get(Metric::Uss), 320 * KB);
+}
+
+#[test]
+fn selects_children() {
+    let procs = read(&Selection {
+        include: Some(Selector::Pid(4242)),
+        match_children: true,
+        ..Selection::default()
+    });
+    let pids: Vec<i32> = procs.iter().map(|p| p.pid).collect();
+    assert_eq!(pids, [4242, 4243]);
+    assert_eq!(procs[1].memory_ext.total().get(Metric::Pss), 48 * KB);
+}
+
+#[test]
+fn categorizes_special_mappings() {
+    let procs = read(&Selection {
+        include: Some(Selector::Pid(4244)),
+        ..Selection::default()
+    });
+    let [proc] = procs.as_slice() else {
+        panic!("expected only 4244, got {procs:?}");
+    };
+    let mem = &proc.memory_ext;
+    let pss = |map: &HashMap<String, MemStats>, name: &str| map[name].pss;
+    assert_eq!(pss(&mem.named_anon_map, "foo"), 12 * KB);
+    assert_eq!(pss(&mem.memfd_map, "x"), 16 * KB);
+    // "/dev/zero (deleted)" is an unnamed shared anonymous mapping, not a deleted file
+    assert_eq!(pss(&mem.anon_shmem_map, ""), 24 * KB);
+    assert_eq!(pss(&mem.anon_shmem_map, "bar"), 8 * KB);
+    // hugetlbfs comes before deleted files, whatever the path is
+    assert_eq!(mem.hugetlb_map.len(), 1);
+    assert_eq!(mem.hugetlb_map["/anon_hugepage"].shared_hugetlb, 2048 * KB);
+
+    let gone = FileMapping::new(
+        false,
+        "/tmp/gone".into(),
+        MMPermissions::READ | MMPermissions::PRIVATE,
+    );
+    assert_eq!(mem.deleted_map.len(), 1);
+    assert_eq!(mem.deleted_map[&gone].pss, 4 * KB);
+    assert_eq!(mem.file_map.len(), 1);
+    assert_eq!(mem.anon_map, Default::default());
+    assert!(mem.other_map.is_empty());
+    assert_eq!(mem.unclassified, Default::default());
+    assert_eq!(mem.total().get(Metric::Pss), 72 * KB);
+}
+
+#[test]
+fn rules_take_precedence_over_the_built_in_categories() {
+    let rules = Rules::from_toml(
+        r#"
+        [[rule]]
+        category = "Anon"
+        path = '^\[anon'
+
+        [[rule]]
+        category = "Shared"
+        perms = "rw-s"
+        "#,
+    )
+    .unwrap();
+    assert_eq!(rules.categories(), ["Anon", "Shared"]);
+
+    let policy = ErrorPolicy::default();
+    let selection = Selection {
+        include: Some(Selector::Pid(4244)),
+        ..Selection::default()
+    };
+    let procs = get_processes_with_root(&fixture_root(), &selection, &policy).unwrap();
+    let options = SmapsOptions {
+        rules,
+        ..SmapsOptions::default()
+    };
+    let procs = get_smaps_with(procs, &policy, &options).unwrap();
+    let mem = &procs[0].memory_ext;
+    // "[anon_shmem:bar]" matches both rules, and the first one wins
+    assert_eq!(mem.custom_map["Anon"].pss, (12 + 8) * KB);
+    assert_eq!(mem.custom_map["Shared"].pss, (16 + 24) * KB);
+    assert_eq!(mem.custom_map["Shared"].shared_hugetlb, 2048 * KB);
+    assert!(mem.named_anon_map.is_empty());
+    assert!(mem.anon_shmem_map.is_empty());
+    assert!(mem.memfd_map.is_empty());
+    assert!(mem.hugetlb_map.is_empty());
+    // the mappings that no rule matches are categorized as usual
+    assert_eq!(mem.deleted_map.len(), 1);
+    assert_eq!(mem.file_map.len(), 1);
+    assert_eq!(mem.total().get(Metric::Pss), 72 * KB);
+}
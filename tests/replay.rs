@@ -0,0 +1,68 @@
+/* Copyright 2025 Andrew Riachi
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, version 3 only.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Records tests/fixtures/proc with smaps-profiler --json and plays it back with --replay.
+
+use std::io::{BufRead, BufReader, Write};
+use std::path::Path;
+use std::process::{Command, Stdio};
+
+/// The first `n` lines that smaps-profiler prints to stdout with `args`, after which it is killed.
+fn first_lines(args: &[&str], n: usize) -> Vec<String> {
+    let root = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/proc");
+    let mut child = Command::new(env!("CARGO_BIN_EXE_smaps-profiler"))
+        .arg("--proc-root")
+        .arg(root)
+        .args(args)
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap();
+    let stdout = BufReader::new(child.stdout.take().unwrap());
+    let lines = stdout.lines().take(n).map(Result::unwrap).collect();
+    child.kill().unwrap();
+    child.wait().unwrap();
+    lines
+}
+
+/// The header and then the rows, which are in no particular order.
+fn sorted(mut tsv: Vec<String>) -> Vec<String> {
+    tsv[1..].sort();
+    tsv
+}
+
+#[test]
+fn replay_prints_what_was_recorded() {
+    let live = sorted(first_lines(&["-M", "Rss"], 4));
+    assert!(live[0].contains("\tHEAP_RSS\t"), "{}", live[0]);
+
+    let recording = first_lines(&["-M", "Rss", "--json"], 1).remove(0);
+    let mut replay = Command::new(env!("CARGO_BIN_EXE_smaps-profiler"))
+        .args(["--replay", "-"])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    // the metric is the one that was recorded, without -M
+    writeln!(replay.stdin.take().unwrap(), "{recording}").unwrap();
+    let output = replay.wait_with_output().unwrap();
+    assert!(output.status.success());
+    let replayed: Vec<String> = String::from_utf8(output.stdout)
+        .unwrap()
+        .lines()
+        .map(String::from)
+        .collect();
+    assert_eq!(sorted(replayed), live);
+}
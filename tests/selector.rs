@@ -0,0 +1,83 @@
+/* Copyright 2025 Andrew Riachi
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, version 3 only.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Selects processes of tests/fixtures/proc with the expressions of --select and --exclude. 4242
+//! is "/usr/bin/fixture --serve" and its child 4243 is "/usr/bin/fixture --worker".
+
+use smaps_profiler::{get_processes_with_root, ErrorPolicy, Selection, Selector};
+use std::path::Path;
+
+fn select(include: &str, exclude: Option<&str>) -> Vec<i32> {
+    let root = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/proc");
+    let selection = Selection {
+        include: Some(include.parse().unwrap()),
+        exclude: exclude.map(|e| e.parse().unwrap()),
+        ..Selection::default()
+    };
+    let mut pids: Vec<i32> = get_processes_with_root(&root, &selection, &ErrorPolicy::default())
+        .unwrap()
+        .iter()
+        .map(|p| p.pid)
+        .collect();
+    pids.sort();
+    pids
+}
+
+#[test]
+fn combines_and_with_or() {
+    assert_eq!(
+        select("comm=fixture and (pid=1 or ppid=4242)", None),
+        [4243]
+    );
+    assert_eq!(select("pid=4243 or ppid=1 and comm=nope", None), [4243]);
+    assert_eq!(
+        select("(pid=4243 or ppid=1) and comm=fixture", None),
+        [4242, 4243]
+    );
+    assert_eq!(select("not not pid=4242", None), [4242]);
+}
+
+#[test]
+fn bare_words_are_cmdline_regexes() {
+    assert_eq!(select("worker", None), [4243]);
+    assert_eq!(select("--(serve|worker)$", None), [4242, 4243]);
+    assert_eq!(select("cmdline='fixture --serve'", None), [4242]);
+    assert_eq!(select("exe=^/usr/bin/ and not serve", None), [4243]);
+}
+
+#[test]
+fn excludes_any_kind_of_condition() {
+    assert_eq!(select("comm=fixture", Some("pid=4242")), [4243]);
+    assert_eq!(select("comm=fixture", Some("worker")), [4242]);
+    assert_eq!(select("comm=fixture", Some("ppid=1 or ppid=4242")), []);
+}
+
+#[test]
+fn rejects_malformed_expressions() {
+    for expr in [
+        "",
+        "pid=4242 and",
+        "(pid=4242",
+        "pid=4242)",
+        "pid=one",
+        "color=red",
+        "=4242",
+        "comm='fixture",
+        "pid=1 pid=2",
+        "comm=(",
+    ] {
+        assert!(expr.parse::<Selector>().is_err(), "accepted \"{expr}\"");
+    }
+}
@@ -0,0 +1,86 @@
+/* Copyright 2025 Andrew Riachi
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, version 3 only.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Checks that what the profiler records can be read back, as --replay and smaps-snapshot do.
+
+use smaps_profiler::{
+    get_processes_with_root, get_smaps_with, ErrorPolicy, FileMapping, MMPermissions, MemStats,
+    MemoryExt, Selection, Selector, SmapsOptions, Vma,
+};
+use std::path::{Path, PathBuf};
+
+fn fixture_root() -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/proc")
+}
+
+#[test]
+fn memory_and_vmas_round_trip() {
+    let policy = ErrorPolicy::default();
+    let selection = Selection {
+        include: Some(Selector::Pid(4242)),
+        ..Selection::default()
+    };
+    let procs = get_processes_with_root(&fixture_root(), &selection, &policy).unwrap();
+    let options = SmapsOptions {
+        keep_vmas: true,
+        ..SmapsOptions::default()
+    };
+    let mut procs = get_smaps_with(procs, &policy, &options).unwrap();
+    let proc = procs.pop().unwrap();
+
+    // the fixture has no deleted files, but they are keyed like file_map
+    let mut memory = proc.memory_ext;
+    let deleted = FileMapping::new(
+        false,
+        "/tmp/gone".into(),
+        MMPermissions::READ | MMPermissions::SHARED,
+    );
+    let stats = MemStats {
+        pss: 4096,
+        ..MemStats::default()
+    };
+    memory.deleted_map.insert(deleted, stats);
+    assert!(!memory.file_map.is_empty());
+
+    let json = serde_json::to_string(&memory).unwrap();
+    assert_eq!(serde_json::from_str::<MemoryExt>(&json).unwrap(), memory);
+
+    let vmas = proc.vmas.unwrap();
+    assert!(vmas.iter().any(|vma| !vma.vm_flags.is_empty()));
+    let json = serde_json::to_string(&vmas).unwrap();
+    assert_eq!(serde_json::from_str::<Vec<Vma>>(&json).unwrap(), vmas);
+}
+
+#[test]
+fn perms_are_written_like_maps() {
+    let file = FileMapping::new(
+        true,
+        "/usr/bin/fixture".into(),
+        MMPermissions::READ | MMPermissions::EXECUTE | MMPermissions::PRIVATE,
+    );
+    let json = serde_json::to_value(&file).unwrap();
+    assert_eq!(json["perms"], "r-x-p");
+}
+
+#[test]
+fn malformed_perms_are_rejected() {
+    for perms in ["rwq", "rwq-p", "r-x-pp", ""] {
+        let json = format!(r#"{{"is_self":false,"path":"/lib/libc.so.6","perms":"{perms}"}}"#);
+        assert!(
+            serde_json::from_str::<FileMapping>(&json).is_err(),
+            "accepted \"{perms}\""
+        );
+    }
+}
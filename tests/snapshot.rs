@@ -0,0 +1,74 @@
+/* Copyright 2025 Andrew Riachi
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, version 3 only.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Compares snapshots of tests/fixtures/proc, as smaps-snapshot --diff does.
+
+use smaps_profiler::{
+    get_processes_with_root, get_smaps, ErrorPolicy, FMask, MMPermissions, MemCategory, Metric,
+    ProcListing, Selection, Snapshot,
+};
+use std::path::Path;
+
+const KB: u64 = 1024;
+
+fn read() -> Vec<ProcListing> {
+    let root = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/proc");
+    let policy = ErrorPolicy::default();
+    let procs = get_processes_with_root(&root, &Selection::default(), &policy).unwrap();
+    get_smaps(procs, &policy).unwrap()
+}
+
+#[test]
+fn processes_are_matched_by_pid_and_cmdline() {
+    let mut procs = read();
+    let before = Snapshot::new(procs.iter().filter(|p| p.pid != 4244).cloned().collect());
+    for proc in &mut procs {
+        match proc.pid {
+            4242 => proc.memory_ext.heap.pss += 4 * KB,
+            // the pid of the worker was reused by another program
+            4243 => proc.cmdline = "/usr/bin/other".to_string(),
+            _ => {}
+        }
+    }
+    let after = Snapshot::new(procs);
+
+    let mut diffs = before.diff(&after);
+    diffs.sort_by(|a, b| (a.pid(), a.cmdline()).cmp(&(b.pid(), b.cmdline())));
+    let summary: Vec<(i32, &str, bool, bool)> = diffs
+        .iter()
+        .map(|d| (d.pid(), d.cmdline(), d.is_new(), d.is_gone()))
+        .collect();
+    assert_eq!(
+        summary,
+        [
+            (4242, "/usr/bin/fixture --serve", false, false),
+            (4243, "/usr/bin/fixture --worker", false, true),
+            (4243, "/usr/bin/other", true, false),
+            (4244, "/opt/oddities/bin/oddities", true, false),
+        ]
+    );
+
+    assert_eq!(diffs[0].change(Metric::Pss), 4 * KB as i64);
+    let mask = FMask::new(true, false, MMPermissions::EXECUTE);
+    let memory = diffs[0].memory_diff(&mask);
+    assert_eq!(
+        memory[&MemCategory::Heap].change(Metric::Pss),
+        4 * KB as i64
+    );
+    assert!(memory
+        .iter()
+        .all(|(category, diff)| *category == MemCategory::Heap || diff.change(Metric::Pss) == 0));
+    assert_eq!(diffs[1].change(Metric::Pss), -48 * KB as i64);
+}